@@ -0,0 +1,189 @@
+//! The Redis 7 style manifest listing the files that make up the
+//! append-only file: one base snapshot followed by incremental logs.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Base,
+    Incr,
+    History,
+}
+
+impl FileType {
+    fn as_str(&self) -> &str {
+        match self {
+            FileType::Base => "b",
+            FileType::Incr => "i",
+            FileType::History => "h",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub kind: FileType,
+}
+
+#[derive(Default)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    pub incrs: Vec<AofFile>,
+    pub history: Vec<AofFile>,
+}
+
+impl Manifest {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut manifest = Manifest::default();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // Lines are `key value` pairs: file <name> seq <n> type <b|i|h>
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if !parts.len().is_multiple_of(2) {
+                return Err(format!("Invalid AOF manifest line: '{}'", line));
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in parts.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => {
+                        kind = match pair[1] {
+                            "b" => Some(FileType::Base),
+                            "i" => Some(FileType::Incr),
+                            "h" => Some(FileType::History),
+                            _ => None,
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(format!("Invalid AOF manifest line: '{}'", line));
+            };
+            let file = AofFile { name, seq, kind };
+            match kind {
+                FileType::Base if manifest.base.is_some() => {
+                    return Err("AOF manifest lists more than one base file".to_string());
+                }
+                FileType::Base => manifest.base = Some(file),
+                FileType::Incr => manifest.incrs.push(file),
+                FileType::History => manifest.history.push(file),
+            }
+        }
+
+        manifest.incrs.sort_by_key(|file| file.seq);
+        Ok(manifest)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let files = self
+            .base
+            .iter()
+            .chain(self.history.iter())
+            .chain(self.incrs.iter());
+        for file in files {
+            out.push_str(&format!(
+                "file {} seq {} type {}\n",
+                file.name,
+                file.seq,
+                file.kind.as_str()
+            ));
+        }
+        out
+    }
+
+    /// Writes the manifest to `path` atomically through a temporary file.
+    pub fn persist(&self, path: &Path) -> Result<(), String> {
+        let temp = path.with_extension("manifest.tmp");
+        let mut file = File::create(&temp)
+            .map_err(|e| format!("Failed to create AOF manifest {:?}: {}", temp, e))?;
+        file.write_all(self.render().as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Failed to write AOF manifest {:?}: {}", temp, e))?;
+        fs::rename(&temp, path)
+            .map_err(|e| format!("Failed to install AOF manifest {:?}: {}", path, e))
+    }
+
+    pub fn next_incr_seq(&self) -> u64 {
+        self.incrs.last().map_or(1, |file| file.seq + 1)
+    }
+
+    pub fn next_base_seq(&self) -> u64 {
+        self.base.as_ref().map_or(1, |file| file.seq + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileType, Manifest};
+
+    #[test]
+    fn parses_a_well_formed_manifest() {
+        let manifest = Manifest::parse(
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n",
+        )
+        .unwrap();
+
+        let base = manifest.base.as_ref().unwrap();
+        assert_eq!(base.name, "appendonly.aof.1.base.rdb");
+        assert!(base.kind == FileType::Base);
+        // Incremental files are replayed in sequence order
+        let seqs: Vec<u64> = manifest.incrs.iter().map(|file| file.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+        assert_eq!(manifest.next_incr_seq(), 4);
+        assert_eq!(manifest.next_base_seq(), 2);
+    }
+
+    #[test]
+    fn renders_what_it_parses() {
+        let content = "file a.1.base.rdb seq 1 type b\n\
+                       file a.1.incr.aof seq 1 type h\n\
+                       file a.2.incr.aof seq 2 type i\n";
+        assert_eq!(Manifest::parse(content).unwrap().render(), content);
+    }
+
+    #[test]
+    fn skips_comments_blank_lines_and_unknown_fields() {
+        let manifest = Manifest::parse(
+            "# written by a newer server\n\
+             \n\
+             type i seq 5 file a.5.incr.aof startoffset 120 endoffset 300\n",
+        )
+        .unwrap();
+
+        assert!(manifest.base.is_none());
+        assert_eq!(manifest.incrs[0].name, "a.5.incr.aof");
+        assert_eq!(manifest.incrs[0].seq, 5);
+    }
+
+    #[test]
+    fn rejects_a_truncated_last_line() {
+        let complete = "file a.1.base.rdb seq 1 type b\n";
+        assert!(Manifest::parse(&format!("{}file a.2.incr.aof seq 2 ty", complete)).is_err());
+        assert!(Manifest::parse(&format!("{}file a.2.incr.aof seq 2", complete)).is_err());
+        assert!(Manifest::parse(&format!("{}file a.2.incr.aof seq", complete)).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert!(Manifest::parse("file a seq x type i\n").is_err());
+        assert!(Manifest::parse("file a seq 1 type z\n").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b\n").is_err());
+    }
+}
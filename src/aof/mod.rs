@@ -0,0 +1,629 @@
+//! Append-only file persistence: every write is logged in RESP form and
+//! replayed at startup.
+//!
+//! The log is split across several files in `appenddirname`, tracked by a
+//! manifest: a base file holding a compacted snapshot (RDB or AOF format)
+//! followed by incremental files with the writes made since. A rewrite
+//! snapshots the dataset into a new base while writes go to a fresh
+//! incremental file, then retires the files the new base replaces.
+
+mod manifest;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{Client, Environment},
+    rdb,
+    resp2::{
+        serialization::{encode_command, try_parse_one_command, Deserialize},
+        Resp2,
+    },
+};
+
+use manifest::{AofFile, FileType, Manifest};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!("Invalid appendfsync policy: '{}'", value)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+}
+
+pub struct Aof {
+    dir: PathBuf,
+    filename: String,
+    manifest: Manifest,
+    file: Arc<File>,
+    policy: FsyncPolicy,
+    /// Size of the whole log right after it was last loaded or rewritten,
+    /// which automatic rewrites measure growth against.
+    base_size: u64,
+    current_size: u64,
+    incr_size: u64,
+    /// Database last selected in the current incremental file.
+    selected_db: Option<usize>,
+    /// Generation of the rewrite in progress, if any.
+    rewrite: Option<u64>,
+    generation: u64,
+    last_rewrite_ok: bool,
+}
+
+impl Aof {
+    pub fn append(&mut self, data: &[u8]) -> Result<(), String> {
+        (&*self.file)
+            .write_all(data)
+            .map_err(|e| format!("Failed to write to append-only file: {}", e))?;
+        self.current_size += data.len() as u64;
+        self.incr_size += data.len() as u64;
+
+        if self.policy == FsyncPolicy::Always {
+            self.file
+                .sync_data()
+                .map_err(|e| format!("Failed to fsync append-only file: {}", e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn selected_db(&self) -> Option<usize> {
+        self.selected_db
+    }
+
+    pub fn set_selected_db(&mut self, db: Option<usize>) {
+        self.selected_db = db;
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite.is_some()
+    }
+
+    pub fn last_rewrite_ok(&self) -> bool {
+        self.last_rewrite_ok
+    }
+
+    pub fn current_size(&self) -> u64 {
+        self.current_size
+    }
+
+    pub fn base_size(&self) -> u64 {
+        self.base_size
+    }
+
+    fn should_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        if self.rewrite.is_some() || percentage == 0 || self.current_size < min_size {
+            return false;
+        }
+        let base = self.base_size.max(1);
+        self.current_size > base && (self.current_size - base) * 100 / base >= percentage
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        manifest_path(&self.dir, &self.filename)
+    }
+
+    /// Starts a new incremental file and records it in the manifest, so that
+    /// writes made from now on survive whatever happens to the rewrite.
+    fn rotate(&mut self) -> Result<(), String> {
+        let _ = self.file.sync_data();
+
+        let seq = self.manifest.next_incr_seq();
+        let name = format!("{}.{}.incr.aof", self.filename, seq);
+        let file = open_incr(&self.dir.join(&name), self.policy)?;
+
+        self.manifest.incrs.push(AofFile {
+            name,
+            seq,
+            kind: FileType::Incr,
+        });
+        self.manifest.persist(&self.manifest_path())?;
+        self.file = file;
+        self.incr_size = 0;
+        self.selected_db = None;
+        Ok(())
+    }
+
+    /// Installs the base file written by rewrite `generation`, retiring the
+    /// previous base and every incremental file but the current one.
+    fn finish_rewrite(
+        &mut self,
+        generation: u64,
+        temp: &Path,
+        base: AofFile,
+        written: Result<u64, String>,
+    ) {
+        if self.rewrite != Some(generation) {
+            // Superseded by a later rewrite
+            let _ = fs::remove_file(temp);
+            return;
+        }
+        self.rewrite = None;
+
+        let installed = written.and_then(|size| {
+            fs::rename(temp, self.dir.join(&base.name))
+                .map_err(|e| format!("Failed to install rewritten AOF base: {}", e))?;
+            Ok(size)
+        });
+        let size = match installed {
+            Ok(size) => size,
+            Err(e) => {
+                eprintln!("Background AOF rewrite failed: {}", e);
+                let _ = fs::remove_file(temp);
+                self.last_rewrite_ok = false;
+                return;
+            }
+        };
+
+        let current = self.manifest.incrs.pop();
+        let retired = self
+            .manifest
+            .base
+            .take()
+            .into_iter()
+            .chain(self.manifest.incrs.drain(..));
+        for mut file in retired {
+            file.kind = FileType::History;
+            self.manifest.history.push(file);
+        }
+        self.manifest.base = Some(base);
+        self.manifest.incrs.extend(current);
+
+        if let Err(e) = self.manifest.persist(&self.manifest_path()) {
+            eprintln!("Background AOF rewrite failed: {}", e);
+            self.last_rewrite_ok = false;
+            return;
+        }
+        for file in self.manifest.history.drain(..) {
+            let _ = fs::remove_file(self.dir.join(&file.name));
+        }
+        if let Err(e) = self.manifest.persist(&self.manifest_path()) {
+            eprintln!("Failed to clean up AOF history: {}", e);
+        }
+
+        self.current_size = size + self.incr_size;
+        self.base_size = self.current_size;
+        self.last_rewrite_ok = true;
+        println!("Background AOF rewrite finished successfully");
+    }
+}
+
+/// Loads the append-only file when `appendonly` is enabled, starts logging
+/// new writes to it and schedules automatic rewrites.
+pub fn start(env: &Arc<Mutex<Environment>>) -> Result<(), String> {
+    let (root, dir, filename, policy, load_truncated) = {
+        let env = env.lock().map_err(|e| e.to_string())?;
+        let config = env.config();
+        if !config.appendonly {
+            return Ok(());
+        }
+        (
+            PathBuf::from(&config.dir),
+            Path::new(&config.dir).join(&config.appenddirname),
+            config.appendfilename.clone(),
+            config.appendfsync,
+            config.aof_load_truncated,
+        )
+    };
+
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create AOF directory {:?}: {}", dir, e))?;
+
+    let path = manifest_path(&dir, &filename);
+    let mut manifest = match fs::read_to_string(&path) {
+        Ok(content) => Manifest::parse(&content)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => upgrade(&root, &dir, &filename)?,
+        Err(e) => return Err(format!("Failed to read AOF manifest {:?}: {}", path, e)),
+    };
+
+    // Only the file written last can have been cut short by a crash
+    let files: Vec<&AofFile> = manifest.base.iter().chain(manifest.incrs.iter()).collect();
+    let mut size = 0;
+    for (i, file) in files.iter().enumerate() {
+        let last = i + 1 == files.len();
+        size += load_file(env, &dir.join(&file.name), last && load_truncated)?;
+    }
+
+    if manifest.incrs.is_empty() {
+        let seq = manifest.next_incr_seq();
+        manifest.incrs.push(AofFile {
+            name: format!("{}.{}.incr.aof", filename, seq),
+            seq,
+            kind: FileType::Incr,
+        });
+        manifest.persist(&path)?;
+    }
+
+    let current = manifest.incrs.last().map(|file| dir.join(&file.name));
+    let file = open_incr(&current.unwrap_or_default(), policy)?;
+    let incr_size = file.metadata().map_or(0, |meta| meta.len());
+
+    env.lock().map_err(|e| e.to_string())?.set_aof(Aof {
+        dir,
+        filename,
+        manifest,
+        file,
+        policy,
+        base_size: size,
+        current_size: size,
+        incr_size,
+        selected_db: None,
+        rewrite: None,
+        generation: 0,
+        last_rewrite_ok: true,
+    });
+
+    let env = Arc::clone(env);
+    thread::spawn(move || cron(env));
+    Ok(())
+}
+
+/// Starts a background rewrite, as requested by BGREWRITEAOF.
+pub fn rewrite(env_arc: &Arc<Mutex<Environment>>, env: &mut Environment) -> Result<(), String> {
+    match env.aof() {
+        None => Err("Append only file is disabled".to_string()),
+        Some(aof) if aof.rewrite_in_progress() => {
+            Err("Background append only file rewriting already in progress".to_string())
+        }
+        Some(_) => start_rewrite(env_arc, env),
+    }
+}
+
+/// Rebuilds the log after the dataset was replaced by a full sync from the
+/// master, abandoning any rewrite of the old dataset still in flight.
+pub fn rewrite_after_sync(
+    env_arc: &Arc<Mutex<Environment>>,
+    env: &mut Environment,
+) -> Result<(), String> {
+    if env.aof().is_none() {
+        return Ok(());
+    }
+    start_rewrite(env_arc, env)
+}
+
+fn start_rewrite(env_arc: &Arc<Mutex<Environment>>, env: &mut Environment) -> Result<(), String> {
+    // The snapshot is taken under the same lock that rotates the incremental
+    // file, so the new base and the new incremental file line up exactly
+    let rdb_preamble = env.config().aof_use_rdb_preamble;
+    let snapshot = if rdb_preamble {
+        rdb::dump(env)
+    } else {
+        dump_commands(env)
+    };
+
+    let aof = env
+        .aof_mut()
+        .ok_or_else(|| "Append only file is disabled".to_string())?;
+    aof.rotate()?;
+    aof.generation += 1;
+    let generation = aof.generation;
+    aof.rewrite = Some(generation);
+
+    let seq = aof.manifest.next_base_seq();
+    let extension = if rdb_preamble { "rdb" } else { "aof" };
+    let base = AofFile {
+        name: format!("{}.{}.base.{}", aof.filename, seq, extension),
+        seq,
+        kind: FileType::Base,
+    };
+    let temp = aof.dir.join(format!(
+        "temp-rewriteaof-bg-{}-{}.aof",
+        std::process::id(),
+        generation
+    ));
+
+    let env_arc = Arc::clone(env_arc);
+    thread::spawn(move || {
+        let written = write_file(&temp, &snapshot).map(|_| snapshot.len() as u64);
+        if let Ok(mut env) = env_arc.lock() {
+            if let Some(aof) = env.aof_mut() {
+                aof.finish_rewrite(generation, &temp, base, written);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Checks ten times a second whether the log grew enough to be rewritten.
+fn cron(env: Arc<Mutex<Environment>>) {
+    loop {
+        thread::sleep(Duration::from_millis(100));
+
+        let Ok(mut guard) = env.lock() else {
+            return;
+        };
+        let percentage = guard.config().auto_aof_rewrite_percentage;
+        let min_size = guard.config().auto_aof_rewrite_min_size as u64;
+        let Some(aof) = guard.aof() else {
+            return;
+        };
+
+        if aof.should_rewrite(percentage, min_size) {
+            let growth = (aof.current_size - aof.base_size) * 100 / aof.base_size.max(1);
+            println!("Starting automatic rewriting of AOF on {}% growth", growth);
+            if let Err(e) = start_rewrite(&env, &mut guard) {
+                eprintln!("Failed to start automatic AOF rewrite: {}", e);
+            }
+        }
+    }
+}
+
+/// Builds the manifest for a log written before manifests existed, moving a
+/// single `appendfilename` file into the AOF directory as its base.
+fn upgrade(root: &Path, dir: &Path, filename: &str) -> Result<Manifest, String> {
+    let mut manifest = Manifest::default();
+    let legacy = root.join(filename);
+    if !legacy.is_file() {
+        return Ok(manifest);
+    }
+
+    let base = AofFile {
+        name: format!("{}.1.base.aof", filename),
+        seq: 1,
+        kind: FileType::Base,
+    };
+    fs::rename(&legacy, dir.join(&base.name))
+        .map_err(|e| format!("Failed to upgrade append-only file {:?}: {}", legacy, e))?;
+    manifest.base = Some(base);
+    manifest.persist(&manifest_path(dir, filename))?;
+    println!("Upgraded {:?} to a multi part append-only file", legacy);
+    Ok(manifest)
+}
+
+fn manifest_path(dir: &Path, filename: &str) -> PathBuf {
+    dir.join(format!("{}.manifest", filename))
+}
+
+fn open_incr(path: &Path, policy: FsyncPolicy) -> Result<Arc<File>, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open append-only file {:?}: {}", path, e))?;
+    let file = Arc::new(file);
+
+    // With `everysec`, a background thread flushes the file to disk once per
+    // second until it is replaced or the log is closed
+    if policy == FsyncPolicy::EverySec {
+        let weak: Weak<File> = Arc::downgrade(&file);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            match weak.upgrade() {
+                Some(file) => {
+                    if let Err(e) = file.sync_data() {
+                        eprintln!("Failed to fsync append-only file: {}", e);
+                    }
+                }
+                None => return,
+            }
+        });
+    }
+
+    Ok(file)
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut file = File::create(path).map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+/// Serializes the dataset as the commands that recreate it.
+fn dump_commands(env: &Environment) -> Vec<u8> {
+    let now = SystemTime::now();
+    let mut out = Vec::new();
+
+    if let Some(scripting) = env.scripting() {
+        for code in scripting.functions().codes() {
+            out.extend(encode_command(&["FUNCTION".to_string(), "LOAD".to_string(), code]));
+        }
+    }
+
+    for (index, db) in env.databases().iter().enumerate() {
+        if db.is_empty() {
+            continue;
+        }
+        out.extend(encode_command(&["SELECT".to_string(), index.to_string()]));
+
+        for (key, entry) in db.values().iter() {
+            let mut args = vec!["SET".to_string(), key.clone(), entry.value.clone()];
+            if let Some(expiry) = entry.expiry {
+                if entry.is_expired(now) {
+                    continue;
+                }
+                let ms = expiry
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis());
+                args.extend(["PXAT".to_string(), ms.to_string()]);
+            }
+            out.extend(encode_command(&args));
+        }
+    }
+
+    out
+}
+
+/// Replays one file of the log, returning its size. A command cut short at
+/// the end of the file (e.g. by a crash mid-write) is dropped and the file
+/// truncated when `load_truncated` is set; otherwise loading fails.
+fn load_file(
+    env: &Arc<Mutex<Environment>>,
+    path: &Path,
+    load_truncated: bool,
+) -> Result<u64, String> {
+    let data =
+        fs::read(path).map_err(|e| format!("Failed to read append-only file {:?}: {}", path, e))?;
+
+    if data.starts_with(b"REDIS") {
+        let mut env = env.lock().map_err(|e| e.to_string())?;
+        rdb::load(&mut env, &data).map_err(|e| format!("Failed to load {:?}: {}", path, e))?;
+        return Ok(data.len() as u64);
+    }
+
+    let valid = replay(env, &data)?;
+    if valid < data.len() {
+        if !load_truncated {
+            return Err(format!(
+                "Append-only file {:?} is truncated at byte {}; set aof-load-truncated yes to recover",
+                path, valid
+            ));
+        }
+
+        eprintln!(
+            "Append-only file {:?} is truncated, discarding the last {} bytes",
+            path,
+            data.len() - valid
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open append-only file {:?}: {}", path, e))?;
+        file.set_len(valid as u64)
+            .map_err(|e| format!("Failed to truncate append-only file {:?}: {}", path, e))?;
+    }
+
+    Ok(valid as u64)
+}
+
+/// Executes every complete command in `data`, returning how many bytes were
+/// consumed. Commands between MULTI and EXEC are only applied once the EXEC is
+/// read, so a transaction cut short by truncation is discarded as a whole.
+fn replay(env: &Arc<Mutex<Environment>>, data: &[u8]) -> Result<usize, String> {
+    let mut guard = env.lock().map_err(|e| e.to_string())?;
+    let mut valid = 0;
+    let mut pos = 0;
+    let mut transaction: Option<Vec<Resp2>> = None;
+    // Stands in for the client that issued the commands, tracking SELECT
+    let mut client = Client::new();
+
+    while let Some((command_bytes, used)) = try_parse_one_command(&data[pos..])
+        .map_err(|e| format!("Corrupt append-only file at byte {}: {}", pos, e))?
+    {
+        pos += used;
+
+        let mut command = Resp2::new(Arc::clone(env));
+        command.deserialize(command_bytes)?;
+
+        let name = command.name().to_uppercase();
+        match (name.as_str(), transaction.as_mut()) {
+            ("MULTI", None) => transaction = Some(Vec::new()),
+            ("EXEC", Some(_)) => {
+                for mut queued in transaction.take().unwrap_or_default() {
+                    queued.replay(&mut guard, &mut client)?;
+                }
+            }
+            (_, Some(queued)) => queued.push(command),
+            (_, None) => command.replay(&mut guard, &mut client)?,
+        }
+
+        if transaction.is_none() {
+            valid = pos;
+        }
+    }
+
+    Ok(valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{load_file, replay};
+    use crate::{
+        common::{Config, Environment},
+        resp2::serialization::encode_command,
+    };
+
+    fn environment() -> Arc<Mutex<Environment>> {
+        Arc::new(Mutex::new(Environment::new(
+            "master".to_string(),
+            0,
+            Config::default(),
+        )))
+    }
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        encode_command(&args)
+    }
+
+    #[test]
+    fn replay_stops_before_a_truncated_command() {
+        let env = environment();
+        let mut data = command(&["SET", "a", "1"]);
+        let complete = data.len();
+        data.extend_from_slice(&command(&["SET", "b", "2"])[..10]);
+
+        assert_eq!(replay(&env, &data).unwrap(), complete);
+        let mut env = env.lock().unwrap();
+        assert_eq!(env.get(0, "a"), Some("1"));
+        assert_eq!(env.get(0, "b"), None);
+    }
+
+    #[test]
+    fn replay_discards_a_transaction_without_exec() {
+        let env = environment();
+        let mut data = command(&["SET", "a", "1"]);
+        let complete = data.len();
+        data.extend(command(&["MULTI"]));
+        data.extend(command(&["SET", "b", "2"]));
+
+        assert_eq!(replay(&env, &data).unwrap(), complete);
+        assert_eq!(env.lock().unwrap().get(0, "b"), None);
+    }
+
+    #[test]
+    fn replay_keeps_values_containing_crlf() {
+        let env = environment();
+        let data = command(&["SET", "a", "one\r\ntwo"]);
+
+        assert_eq!(replay(&env, &data).unwrap(), data.len());
+        assert_eq!(env.lock().unwrap().get(0, "a"), Some("one\r\ntwo"));
+    }
+
+    #[test]
+    fn load_file_recovers_from_a_truncated_tail() {
+        let path = std::env::temp_dir().join(format!("aof-truncated-{}.aof", std::process::id()));
+        let mut data = command(&["SET", "a", "1"]);
+        let complete = data.len();
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+        fs::write(&path, &data).unwrap();
+
+        assert!(load_file(&environment(), &path, false).is_err());
+        assert_eq!(
+            load_file(&environment(), &path, true).unwrap(),
+            complete as u64
+        );
+        assert_eq!(fs::read(&path).unwrap().len(), complete);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+/// Circular buffer holding the most recent bytes of the replication stream,
+/// used to serve partial resynchronizations.
+pub struct ReplicationBacklog {
+    buffer: Vec<u8>,
+    idx: usize,
+    histlen: usize,
+    offset: u64,
+}
+
+impl ReplicationBacklog {
+    /// Creates an empty backlog whose next byte will have replication offset `offset`.
+    pub fn new(size: usize, offset: u64) -> Self {
+        ReplicationBacklog {
+            buffer: vec![0; size],
+            idx: 0,
+            histlen: 0,
+            offset,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.histlen
+    }
+
+    /// Replication offset of the first byte still held in the backlog.
+    pub fn first_offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn feed(&mut self, mut data: &[u8]) {
+        let size = self.buffer.len();
+
+        while !data.is_empty() {
+            let chunk = (size - self.idx).min(data.len());
+            self.buffer[self.idx..self.idx + chunk].copy_from_slice(&data[..chunk]);
+            self.idx = (self.idx + chunk) % size;
+            self.histlen += chunk;
+            data = &data[chunk..];
+        }
+
+        if self.histlen > size {
+            self.offset += (self.histlen - size) as u64;
+            self.histlen = size;
+        }
+    }
+
+    /// Returns every byte from replication offset `from` up to the end of the
+    /// stream, or `None` if that range is no longer (or not yet) available.
+    pub fn range_from(&self, from: u64) -> Option<Vec<u8>> {
+        let end = self.offset + self.histlen as u64;
+        if from < self.offset || from > end {
+            return None;
+        }
+
+        let size = self.buffer.len();
+        let skip = (from - self.offset) as usize;
+        let len = self.histlen - skip;
+        let start = (self.idx + size - self.histlen + skip) % size;
+
+        let mut out = Vec::with_capacity(len);
+        let first = (size - start).min(len);
+        out.extend_from_slice(&self.buffer[start..start + first]);
+        out.extend_from_slice(&self.buffer[..len - first]);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplicationBacklog;
+
+    #[test]
+    fn serves_the_stream_before_wrapping() {
+        let mut backlog = ReplicationBacklog::new(16, 100);
+        backlog.feed(b"hello ");
+        backlog.feed(b"world");
+
+        assert_eq!(backlog.first_offset(), 100);
+        assert_eq!(backlog.histlen(), 11);
+        assert_eq!(backlog.range_from(100).unwrap(), b"hello world");
+        assert_eq!(backlog.range_from(106).unwrap(), b"world");
+    }
+
+    #[test]
+    fn keeps_the_most_recent_bytes_once_wrapped() {
+        let mut backlog = ReplicationBacklog::new(8, 0);
+        backlog.feed(b"abcde");
+        backlog.feed(b"fghijk");
+
+        assert_eq!(backlog.first_offset(), 3);
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.range_from(3).unwrap(), b"defghijk");
+        assert_eq!(backlog.range_from(9).unwrap(), b"jk");
+    }
+
+    #[test]
+    fn feeding_more_than_its_size_at_once() {
+        let mut backlog = ReplicationBacklog::new(4, 10);
+        backlog.feed(b"0123456789");
+
+        assert_eq!(backlog.first_offset(), 16);
+        assert_eq!(backlog.range_from(16).unwrap(), b"6789");
+    }
+
+    #[test]
+    fn offset_just_before_the_start_is_gone() {
+        let mut backlog = ReplicationBacklog::new(8, 0);
+        backlog.feed(b"abcdefghijk");
+
+        assert!(backlog.range_from(2).is_none());
+        assert!(backlog.range_from(3).is_some());
+    }
+
+    #[test]
+    fn offset_at_the_end_has_nothing_missing() {
+        let mut backlog = ReplicationBacklog::new(8, 0);
+        assert_eq!(backlog.range_from(0).unwrap(), b"");
+
+        backlog.feed(b"abcdefghijk");
+        assert_eq!(backlog.range_from(11).unwrap(), b"");
+    }
+
+    #[test]
+    fn offset_beyond_the_end_is_not_served() {
+        let mut backlog = ReplicationBacklog::new(8, 0);
+        backlog.feed(b"abc");
+
+        assert!(backlog.range_from(4).is_none());
+        assert!(backlog.range_from(u64::MAX).is_none());
+    }
+}
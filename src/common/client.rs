@@ -0,0 +1,281 @@
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use super::{Outbox, OutputBufferLimit, Subscription};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Ids of the clients with a live connection. Kept out of the environment,
+/// so connecting never waits on a script holding it.
+static CONNECTED: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+/// Per-connection state that outlives a single command.
+#[derive(Default)]
+pub struct Client {
+    id: u64,
+    is_master: bool,
+    /// Set once the connection asked for the replication stream with PSYNC.
+    is_replica: bool,
+    listening_port: Option<u16>,
+    last_write_offset: u64,
+    eof_capable: bool,
+    db: usize,
+    /// Commands queued since MULTI, or None outside a transaction.
+    multi: Option<Vec<Vec<String>>>,
+    /// Whether a command was rejected while queuing, which aborts the EXEC.
+    multi_failed: bool,
+    /// Keys watched with WATCH, by database.
+    watched: Vec<(usize, String)>,
+    /// Writer of the connection, which fake clients such as the ones running
+    /// scripts or loading the AOF don't have.
+    outbox: Option<Outbox>,
+    /// Limits on the output of the connection, while it is not subscribed
+    /// and while it is. Checked without the environment, which a script may
+    /// be holding.
+    output_limits: (OutputBufferLimit, OutputBufferLimit),
+    soft_limit_since: Option<SystemTime>,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    shard_channels: Vec<String>,
+    /// RESP version negotiated with HELLO.
+    protocol: u8,
+    /// Set by CLIENT CACHING for the next command of a tracking client.
+    caching: Option<bool>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: 2,
+            ..Client::default()
+        }
+    }
+
+    /// State for the link a replica keeps open with its master, whose stream
+    /// continues in database `db`.
+    pub fn master(db: usize) -> Self {
+        Client {
+            is_master: true,
+            db,
+            ..Client::new()
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.is_master
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.is_replica
+    }
+
+    pub fn set_replica(&mut self) {
+        self.is_replica = true;
+    }
+
+    pub fn listening_port(&self) -> Option<u16> {
+        self.listening_port
+    }
+
+    pub fn set_listening_port(&mut self, port: u16) {
+        self.listening_port = Some(port);
+    }
+
+    /// Whether a replica announced it can receive EOF-framed (diskless) RDB transfers.
+    pub fn eof_capable(&self) -> bool {
+        self.eof_capable
+    }
+
+    pub fn set_eof_capable(&mut self) {
+        self.eof_capable = true;
+    }
+
+    /// Replication offset right after this client's most recent write.
+    pub fn last_write_offset(&self) -> u64 {
+        self.last_write_offset
+    }
+
+    pub fn set_last_write_offset(&mut self, offset: u64) {
+        self.last_write_offset = offset;
+    }
+
+    /// Database selected with SELECT.
+    pub fn db(&self) -> usize {
+        self.db
+    }
+
+    pub fn select(&mut self, db: usize) {
+        self.db = db;
+    }
+
+    pub fn in_multi(&self) -> bool {
+        self.multi.is_some()
+    }
+
+    pub fn begin_multi(&mut self) {
+        self.multi = Some(Vec::new());
+        self.multi_failed = false;
+    }
+
+    pub fn queue(&mut self, args: Vec<String>) {
+        if let Some(queued) = self.multi.as_mut() {
+            queued.push(args);
+        }
+    }
+
+    /// Marks the current transaction as failed, if there is one.
+    pub fn fail_multi(&mut self) {
+        if self.multi.is_some() {
+            self.multi_failed = true;
+        }
+    }
+
+    /// Ends the transaction, returning the queued commands and whether queuing
+    /// any of them failed.
+    pub fn take_multi(&mut self) -> Option<(Vec<Vec<String>>, bool)> {
+        let queued = self.multi.take()?;
+        Some((queued, std::mem::take(&mut self.multi_failed)))
+    }
+
+    /// Records `key` as watched, returning false if it already was.
+    pub fn watch(&mut self, db: usize, key: &str) -> bool {
+        if self.watched.iter().any(|(d, k)| *d == db && k == key) {
+            return false;
+        }
+        self.watched.push((db, key.to_string()));
+        true
+    }
+
+    pub fn watched(&self) -> &[(usize, String)] {
+        &self.watched
+    }
+
+    pub fn take_watched(&mut self) -> Vec<(usize, String)> {
+        std::mem::take(&mut self.watched)
+    }
+
+    pub fn outbox(&self) -> Option<&Outbox> {
+        self.outbox.as_ref()
+    }
+
+    pub fn set_output_limits(&mut self, normal: OutputBufferLimit, pubsub: OutputBufferLimit) {
+        self.output_limits = (normal, pubsub);
+    }
+
+    /// Whether the output queued for the client broke the limits of its
+    /// class. Replicas are only limited on the replication stream, and the
+    /// link to our master not at all.
+    pub fn output_limit_exceeded(&mut self) -> bool {
+        if self.is_master || self.is_replica {
+            return false;
+        }
+        let Some(outbox) = self.outbox.as_ref() else {
+            return false;
+        };
+        let limit = match self.subscription_count() {
+            0 => &self.output_limits.0,
+            _ => &self.output_limits.1,
+        };
+        limit.exceeded(outbox.buffered(), &mut self.soft_limit_since)
+    }
+
+    /// Gives the client its connection, after which it counts as connected
+    /// until closed.
+    pub fn set_outbox(&mut self, outbox: Outbox) {
+        self.outbox = Some(outbox);
+        if let Ok(mut connected) = CONNECTED.lock() {
+            connected.insert(self.id);
+        }
+    }
+
+    /// Forgets the connection of a client that hung up.
+    pub fn close(&mut self) {
+        if self.outbox.take().is_some() {
+            if let Ok(mut connected) = CONNECTED.lock() {
+                connected.remove(&self.id);
+            }
+        }
+    }
+
+    pub fn is_connected(id: u64) -> bool {
+        CONNECTED
+            .lock()
+            .is_ok_and(|connected| connected.contains(&id))
+    }
+
+    pub fn subscriptions(&self, kind: Subscription) -> &[String] {
+        match kind {
+            Subscription::Channel => &self.channels,
+            Subscription::Pattern => &self.patterns,
+            Subscription::Shard => &self.shard_channels,
+        }
+    }
+
+    fn subscriptions_mut(&mut self, kind: Subscription) -> &mut Vec<String> {
+        match kind {
+            Subscription::Channel => &mut self.channels,
+            Subscription::Pattern => &mut self.patterns,
+            Subscription::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Records a subscription, returning false if it already existed.
+    pub fn subscribe(&mut self, kind: Subscription, name: &str) -> bool {
+        let names = self.subscriptions_mut(kind);
+        if names.iter().any(|n| n == name) {
+            return false;
+        }
+        names.push(name.to_string());
+        true
+    }
+
+    /// Drops a subscription, returning false if there was none.
+    pub fn unsubscribe(&mut self, kind: Subscription, name: &str) -> bool {
+        let names = self.subscriptions_mut(kind);
+        let before = names.len();
+        names.retain(|n| n != name);
+        names.len() != before
+    }
+
+    /// Number of subscriptions of any kind. While above zero the connection
+    /// is in subscribed mode and may only run a few commands.
+    pub fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    /// The count confirmations of a `kind` of subscription report: shard
+    /// channels are counted apart from channels and patterns.
+    pub fn subscription_count_of(&self, kind: Subscription) -> usize {
+        match kind {
+            Subscription::Shard => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.protocol = protocol;
+    }
+
+    pub fn caching(&self) -> Option<bool> {
+        self.caching
+    }
+
+    pub fn set_caching(&mut self, caching: Option<bool>) {
+        self.caching = caching;
+    }
+}
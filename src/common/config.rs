@@ -0,0 +1,392 @@
+use std::time::SystemTime;
+
+use super::{keyspace_events_to_string, parse_keyspace_events, LogLevel};
+use crate::aof::FsyncPolicy;
+
+/// Limits on the bytes queued for a client: it is disconnected as soon as
+/// `hard` is exceeded, or after staying above `soft` for `soft_seconds`.
+/// A limit of 0 disables it.
+#[derive(Clone, Copy, Default)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Whether `buffered` bytes break the limits. `soft_limit_since` tracks
+    /// when the soft limit was first exceeded and is reset once below it.
+    pub fn exceeded(&self, buffered: usize, soft_limit_since: &mut Option<SystemTime>) -> bool {
+        if self.hard > 0 && buffered > self.hard {
+            return true;
+        }
+
+        if self.soft > 0 && buffered > self.soft {
+            let since = *soft_limit_since.get_or_insert_with(SystemTime::now);
+            let elapsed = since.elapsed().map_or(0, |d| d.as_secs());
+            return elapsed >= self.soft_seconds;
+        }
+
+        *soft_limit_since = None;
+        false
+    }
+}
+
+/// Parameters shown by CONFIG GET, by their canonical names.
+pub const PARAMETERS: [&str; 24] = [
+    "loglevel",
+    "databases",
+    "maxmemory",
+    "maxmemory-samples",
+    "maxmemory-policy",
+    "lfu-log-factor",
+    "lfu-decay-time",
+    "repl-backlog-size",
+    "replica-read-only",
+    "repl-diskless-sync",
+    "repl-diskless-sync-delay",
+    "busy-reply-threshold",
+    "notify-keyspace-events",
+    "dir",
+    "appendonly",
+    "appendfilename",
+    "appenddirname",
+    "appendfsync",
+    "aof-load-truncated",
+    "aof-use-rdb-preamble",
+    "auto-aof-rewrite-percentage",
+    "auto-aof-rewrite-min-size",
+    "client-output-buffer-limit",
+    "proto-max-bulk-len",
+];
+
+/// How keys are chosen for eviction once `maxmemory` is reached.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        Ok(match value.to_lowercase().as_str() {
+            "noeviction" => MaxmemoryPolicy::NoEviction,
+            "allkeys-lru" => MaxmemoryPolicy::AllKeysLru,
+            "volatile-lru" => MaxmemoryPolicy::VolatileLru,
+            "allkeys-lfu" => MaxmemoryPolicy::AllKeysLfu,
+            "volatile-lfu" => MaxmemoryPolicy::VolatileLfu,
+            "allkeys-random" => MaxmemoryPolicy::AllKeysRandom,
+            "volatile-random" => MaxmemoryPolicy::VolatileRandom,
+            "volatile-ttl" => MaxmemoryPolicy::VolatileTtl,
+            _ => return Err(format!("Invalid maxmemory-policy: '{}'", value)),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn is_lru(&self) -> bool {
+        matches!(self, MaxmemoryPolicy::AllKeysLru | MaxmemoryPolicy::VolatileLru)
+    }
+
+    pub fn is_lfu(&self) -> bool {
+        matches!(self, MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu)
+    }
+}
+
+pub struct Config {
+    pub loglevel: LogLevel,
+    pub databases: usize,
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    pub maxmemory_samples: usize,
+    pub lfu_log_factor: u32,
+    pub lfu_decay_time: u64,
+    /// Milliseconds a script runs before other clients are answered BUSY.
+    pub busy_reply_threshold: u64,
+    pub repl_backlog_size: usize,
+    pub replica_read_only: bool,
+    pub normal_output_buffer_limit: OutputBufferLimit,
+    pub replica_output_buffer_limit: OutputBufferLimit,
+    pub pubsub_output_buffer_limit: OutputBufferLimit,
+    /// Classes of keyspace notifications published, see [`NOTIFY_ALL`](super::NOTIFY_ALL).
+    pub notify_keyspace_events: u32,
+    pub repl_diskless_sync: bool,
+    pub repl_diskless_sync_delay: u64,
+    pub dir: String,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appenddirname: String,
+    pub appendfsync: FsyncPolicy,
+    pub aof_load_truncated: bool,
+    pub aof_use_rdb_preamble: bool,
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: usize,
+    /// Longest bulk string a client may send.
+    pub proto_max_bulk_len: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            loglevel: LogLevel::Notice,
+            databases: 16,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            busy_reply_threshold: 5000,
+            repl_backlog_size: 1024 * 1024,
+            replica_read_only: true,
+            normal_output_buffer_limit: OutputBufferLimit {
+                hard: 0,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            replica_output_buffer_limit: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub_output_buffer_limit: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            notify_keyspace_events: 0,
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: 5,
+            dir: ".".to_string(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: FsyncPolicy::EverySec,
+            aof_load_truncated: true,
+            aof_use_rdb_preamble: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Limits on what is queued for a client that is not a replica, which
+    /// are the `pubsub` ones while it is subscribed to anything.
+    pub fn client_output_buffer_limit(&self, subscribed: bool) -> &OutputBufferLimit {
+        match subscribed {
+            true => &self.pubsub_output_buffer_limit,
+            false => &self.normal_output_buffer_limit,
+        }
+    }
+
+    /// The value of parameter `name` as CONFIG GET shows it, which [`Config::set`]
+    /// accepts back.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let yes_no = |value: bool| if value { "yes" } else { "no" }.to_string();
+        Some(match name.to_lowercase().as_str() {
+            "loglevel" => self.loglevel.name().to_string(),
+            "databases" => self.databases.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_string(),
+            "lfu-log-factor" => self.lfu_log_factor.to_string(),
+            "lfu-decay-time" => self.lfu_decay_time.to_string(),
+            "repl-backlog-size" => self.repl_backlog_size.to_string(),
+            "replica-read-only" | "slave-read-only" => yes_no(self.replica_read_only),
+            "repl-diskless-sync" => yes_no(self.repl_diskless_sync),
+            "repl-diskless-sync-delay" => self.repl_diskless_sync_delay.to_string(),
+            "busy-reply-threshold" | "lua-time-limit" => self.busy_reply_threshold.to_string(),
+            "notify-keyspace-events" => keyspace_events_to_string(self.notify_keyspace_events),
+            "dir" => self.dir.clone(),
+            "appendonly" => yes_no(self.appendonly),
+            "appendfilename" => self.appendfilename.clone(),
+            "appenddirname" => self.appenddirname.clone(),
+            "appendfsync" => self.appendfsync.name().to_string(),
+            "aof-load-truncated" => yes_no(self.aof_load_truncated),
+            "aof-use-rdb-preamble" => yes_no(self.aof_use_rdb_preamble),
+            "auto-aof-rewrite-percentage" => self.auto_aof_rewrite_percentage.to_string(),
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size.to_string(),
+            "client-output-buffer-limit" => [
+                ("normal", &self.normal_output_buffer_limit),
+                ("slave", &self.replica_output_buffer_limit),
+                ("pubsub", &self.pubsub_output_buffer_limit),
+            ]
+            .iter()
+            .map(|(class, limit)| {
+                format!(
+                    "{} {} {} {}",
+                    class, limit.hard, limit.soft, limit.soft_seconds
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            _ => return None,
+        })
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_lowercase().as_str() {
+            "loglevel" => self.loglevel = LogLevel::parse(value)?,
+            "databases" => {
+                self.databases = match value.parse::<usize>() {
+                    Ok(databases) if databases > 0 => databases,
+                    _ => return Err(format!("Invalid databases: '{}'", value)),
+                };
+            }
+            "maxmemory" => self.maxmemory = parse_memory(value)?,
+            "maxmemory-samples" => {
+                self.maxmemory_samples = match value.parse::<usize>() {
+                    Ok(samples) if samples > 0 => samples,
+                    _ => return Err(format!("Invalid maxmemory-samples: '{}'", value)),
+                };
+            }
+            "maxmemory-policy" => self.maxmemory_policy = MaxmemoryPolicy::parse(value)?,
+            "lfu-log-factor" => {
+                self.lfu_log_factor = value
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid lfu-log-factor: '{}'", value))?;
+            }
+            "lfu-decay-time" => {
+                self.lfu_decay_time = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid lfu-decay-time: '{}'", value))?;
+            }
+            "repl-backlog-size" => {
+                self.repl_backlog_size = parse_memory(value)?;
+                if self.repl_backlog_size == 0 {
+                    return Err("repl-backlog-size must be greater than 0".to_string());
+                }
+            }
+            "replica-read-only" | "slave-read-only" => {
+                self.replica_read_only = parse_bool(value)?;
+            }
+            "repl-diskless-sync" => {
+                self.repl_diskless_sync = parse_bool(value)?;
+            }
+            "repl-diskless-sync-delay" => {
+                self.repl_diskless_sync_delay = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid repl-diskless-sync-delay: '{}'", value))?;
+            }
+            "busy-reply-threshold" | "lua-time-limit" => {
+                self.busy_reply_threshold = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid busy-reply-threshold: '{}'", value))?;
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = parse_keyspace_events(value)?;
+            }
+            "dir" => self.dir = value.to_string(),
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "appendfilename" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err(format!("Invalid appendfilename: '{}'", value));
+                }
+                self.appendfilename = value.to_string();
+            }
+            "appenddirname" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err(format!("Invalid appenddirname: '{}'", value));
+                }
+                self.appenddirname = value.to_string();
+            }
+            "appendfsync" => self.appendfsync = FsyncPolicy::parse(value)?,
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "aof-use-rdb-preamble" => self.aof_use_rdb_preamble = parse_bool(value)?,
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid auto-aof-rewrite-percentage: '{}'", value))?;
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(value)?;
+            }
+            "proto-max-bulk-len" => {
+                self.proto_max_bulk_len = parse_memory(value)?;
+                if self.proto_max_bulk_len < 1024 * 1024 {
+                    return Err("proto-max-bulk-len must be at least 1mb".to_string());
+                }
+            }
+            "client-output-buffer-limit" => {
+                // <class> <hard> <soft> <soft seconds>, repeated for each class
+                let parts: Vec<&str> = value.split_whitespace().collect();
+                if parts.is_empty() || !parts.len().is_multiple_of(4) {
+                    return Err("Wrong number of arguments in buffer limit configuration".to_string());
+                }
+
+                for chunk in parts.chunks(4) {
+                    let limit = OutputBufferLimit {
+                        hard: parse_memory(chunk[1])?,
+                        soft: parse_memory(chunk[2])?,
+                        soft_seconds: chunk[3]
+                            .parse::<u64>()
+                            .map_err(|_| format!("Invalid soft limit seconds: '{}'", chunk[3]))?,
+                    };
+
+                    match chunk[0].to_lowercase().as_str() {
+                        "replica" | "slave" => self.replica_output_buffer_limit = limit,
+                        "pubsub" => self.pubsub_output_buffer_limit = limit,
+                        "normal" => self.normal_output_buffer_limit = limit,
+                        class => return Err(format!("Invalid client class '{}'", class)),
+                    }
+                }
+            }
+            _ => return Err(format!("Unknown configuration option '{}'", name)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses sizes such as `512`, `64kb` or `1mb` into bytes.
+pub fn parse_memory(value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+
+    let base = digits
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid memory value: '{}'", value))?;
+
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid memory unit in '{}'", value)),
+    };
+
+    Ok(base * multiplier)
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("Expected yes or no, got '{}'", value)),
+    }
+}
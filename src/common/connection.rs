@@ -0,0 +1,145 @@
+//! Output side of a connection: every reply and pushed message is queued to
+//! a task of its own that writes it, so nothing ever waits on a slow peer
+//! while holding the environment.
+
+use std::{
+    net::{Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{tcp::OwnedReadHalf, TcpStream},
+    sync::{
+        mpsc::{self, UnboundedSender},
+        Notify,
+    },
+};
+
+#[derive(Clone)]
+pub struct Outbox {
+    /// Handle on the same socket, to close it from any thread.
+    socket: Arc<std::net::TcpStream>,
+    output: UnboundedSender<Vec<u8>>,
+    buffered: Arc<AtomicUsize>,
+    /// Bytes ever queued, written or not.
+    pushed: Arc<AtomicU64>,
+    /// Output held back while corked, to be written at once. Messages pushed
+    /// by other connections meanwhile are held too, so they stay in order
+    /// with the replies.
+    corked: Arc<Mutex<Option<Vec<u8>>>>,
+    /// Notified whenever the writer flushed something, or gave up.
+    drained: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Spawns the writer of `stream`, returning the half it is read from.
+    /// Must be called from within the runtime.
+    pub fn spawn(stream: TcpStream) -> Result<(OwnedReadHalf, Outbox), String> {
+        let stream = stream
+            .into_std()
+            .map_err(|e| format!("Failed to detach stream: {}", e))?;
+        let socket = Arc::new(
+            stream
+                .try_clone()
+                .map_err(|e| format!("Failed to clone stream: {}", e))?,
+        );
+        let stream =
+            TcpStream::from_std(stream).map_err(|e| format!("Failed to register stream: {}", e))?;
+
+        let (reader, mut writer) = stream.into_split();
+        let (output, mut queue) = mpsc::unbounded_channel::<Vec<u8>>();
+        let buffered = Arc::new(AtomicUsize::new(0));
+
+        let drained = Arc::new(Notify::new());
+
+        let pending = Arc::clone(&buffered);
+        let closing = Arc::clone(&socket);
+        let flushed = Arc::clone(&drained);
+        tokio::spawn(async move {
+            while let Some(chunk) = queue.recv().await {
+                if writer.write_all(&chunk).await.is_err() {
+                    break;
+                }
+                pending.fetch_sub(chunk.len(), Ordering::Relaxed);
+                flushed.notify_one();
+            }
+            drop(queue);
+            let _ = closing.shutdown(Shutdown::Both);
+            flushed.notify_one();
+        });
+
+        let outbox = Outbox {
+            socket,
+            output,
+            buffered,
+            pushed: Arc::new(AtomicU64::new(0)),
+            corked: Arc::new(Mutex::new(None)),
+            drained,
+        };
+        Ok((reader, outbox))
+    }
+
+    /// Queues `data` without blocking, returning the bytes now waiting to be
+    /// written, or None once the connection is gone.
+    pub fn push(&self, data: &[u8]) -> Option<usize> {
+        let mut corked = self.corked.lock().ok()?;
+        // Counted before sending, so the writer never subtracts bytes we did not add yet
+        let buffered = self.buffered.fetch_add(data.len(), Ordering::Relaxed) + data.len();
+        self.pushed.fetch_add(data.len() as u64, Ordering::Relaxed);
+        match corked.as_mut() {
+            Some(held) => held.extend_from_slice(data),
+            None => self.output.send(data.to_vec()).ok()?,
+        }
+        Some(buffered)
+    }
+
+    /// Holds back everything queued from now on until [`Outbox::uncork`].
+    pub fn cork(&self) {
+        if let Ok(mut corked) = self.corked.lock() {
+            corked.get_or_insert_with(Vec::new);
+        }
+    }
+
+    /// Hands what was held back to the writer as a single chunk. Returns
+    /// false once the connection is gone.
+    pub fn uncork(&self) -> bool {
+        let Ok(mut corked) = self.corked.lock() else {
+            return false;
+        };
+        match corked.take() {
+            Some(held) if !held.is_empty() => self.output.send(held).is_ok(),
+            _ => !self.output.is_closed(),
+        }
+    }
+
+    /// Waits until at most `limit` bytes are left to write, or the
+    /// connection is gone.
+    pub async fn drained(&self, limit: usize) {
+        while self.buffered() > limit && !self.output.is_closed() {
+            self.drained.notified().await;
+        }
+    }
+
+    /// Bytes queued that were not written to the socket yet.
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Bytes queued since the connection was opened.
+    pub fn pushed(&self) -> u64 {
+        self.pushed.load(Ordering::Relaxed)
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.socket.peer_addr().ok()
+    }
+
+    /// Closes the connection, which ends both its reader and its writer.
+    pub fn disconnect(&self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
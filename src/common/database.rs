@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+
+use super::Dict;
+
+/// Starting value of the access counter, so new keys are not evicted first.
+const LFU_INIT_VAL: u8 = 5;
+
+/// Estimated bytes a key costs beyond its key and value bytes: the table
+/// entry, the object headers and the access metadata.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Integers below this are shared objects in Redis, reported with a fixed refcount.
+const SHARED_INTEGERS: i64 = 10000;
+
+/// A value in the keyspace together with its expiry and the access metadata
+/// eviction policies rely on.
+pub struct Entry {
+    pub value: String,
+    pub expiry: Option<SystemTime>,
+    /// Time of the last access, for LRU policies and OBJECT IDLETIME.
+    accessed: Instant,
+    /// Logarithmic access counter, for LFU policies and OBJECT FREQ.
+    counter: u8,
+    /// Time the counter last decayed.
+    decayed: Instant,
+}
+
+impl Entry {
+    pub fn new(value: String, expiry: Option<SystemTime>) -> Self {
+        let now = Instant::now();
+        Entry {
+            value,
+            expiry,
+            accessed: now,
+            counter: LFU_INIT_VAL,
+            decayed: now,
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiry.is_some_and(|expiry| now >= expiry)
+    }
+
+    /// Records an access: refreshes the LRU clock and bumps the LFU counter,
+    /// which grows ever more slowly the higher it gets (`log_factor`).
+    pub fn touch(&mut self, log_factor: u32, decay_minutes: u64) {
+        self.counter = self.frequency(decay_minutes);
+        self.decayed = Instant::now();
+        self.accessed = Instant::now();
+
+        if self.counter == u8::MAX {
+            return;
+        }
+        let base = self.counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let probability = 1.0 / (base * log_factor as f64 + 1.0);
+        if rand::rng().random::<f64>() < probability {
+            self.counter += 1;
+        }
+    }
+
+    /// The access counter after decaying it by one for every `decay_minutes`
+    /// elapsed since it last changed.
+    pub fn frequency(&self, decay_minutes: u64) -> u8 {
+        if decay_minutes == 0 {
+            return self.counter;
+        }
+        let periods = self.decayed.elapsed().as_secs() / 60 / decay_minutes;
+        self.counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.accessed.elapsed()
+    }
+
+    /// The internal representation Redis would pick for this value.
+    pub fn encoding(&self) -> &'static str {
+        if self.value.len() <= 20 && self.value.parse::<i64>().is_ok() {
+            "int"
+        } else if self.value.len() <= 44 {
+            "embstr"
+        } else {
+            "raw"
+        }
+    }
+
+    /// Estimated memory used by this entry stored under `key`.
+    pub fn footprint(&self, key: &str) -> usize {
+        key.len() + self.value.len() + ENTRY_OVERHEAD
+    }
+
+    /// Whether Redis would serve this value from its pool of shared integers.
+    pub fn is_shared(&self) -> bool {
+        self.encoding() == "int"
+            && self
+                .value
+                .parse::<i64>()
+                .is_ok_and(|n| (0..SHARED_INTEGERS).contains(&n))
+    }
+}
+
+/// One of the numbered keyspaces selected with SELECT.
+#[derive(Default)]
+pub struct Database {
+    values: Dict<String, Entry>,
+    /// Estimated memory used by the keys and values.
+    memory: usize,
+    /// Number of keys with an expiration set.
+    volatile: usize,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Database::default()
+    }
+
+    pub fn values(&self) -> &Dict<String, Entry> {
+        &self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Number of keys with an expiration set.
+    pub fn expires(&self) -> usize {
+        self.volatile
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.memory
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.values.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.values.get_mut(key)
+    }
+
+    /// Stores `value` at `key`. Overwriting a key keeps its access counter,
+    /// as rewriting a value says nothing about how often it is used.
+    pub fn insert(&mut self, key: String, value: String, expiry: Option<SystemTime>) {
+        let mut entry = Entry::new(value, expiry);
+        if let Some(previous) = self.values.get(&key) {
+            entry.counter = previous.counter;
+            entry.decayed = previous.decayed;
+        }
+        self.insert_entry(key, entry);
+    }
+
+    pub fn insert_entry(&mut self, key: String, entry: Entry) {
+        self.memory += entry.footprint(&key);
+        self.volatile += entry.expiry.is_some() as usize;
+
+        let key_len = key.len();
+        if let Some(previous) = self.values.insert(key, entry) {
+            self.memory -= key_len + previous.value.len() + ENTRY_OVERHEAD;
+            self.volatile -= previous.expiry.is_some() as usize;
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.values.remove(key)?;
+        self.memory -= entry.footprint(key);
+        self.volatile -= entry.expiry.is_some() as usize;
+        Some(entry)
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.memory = 0;
+        self.volatile = 0;
+    }
+
+    pub fn random(&self) -> Option<(&String, &Entry)> {
+        self.values.random()
+    }
+
+    /// One step of a SCAN over the keys, see [`Dict::scan`].
+    pub fn scan(&self, cursor: u64, visit: impl FnMut(&String, &Entry)) -> u64 {
+        self.values.scan(cursor, visit)
+    }
+}
@@ -0,0 +1,234 @@
+//! A chained hash table with a power-of-two number of buckets, modelled on
+//! Redis' `dict`. Owning the bucket layout is what makes SCAN possible: its
+//! cursor walks bucket indexes in reverse-binary order, so every entry present
+//! for the whole iteration is returned even if the table grows or shrinks
+//! between calls.
+
+use rand::Rng;
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+    mem,
+};
+
+const MIN_BUCKETS: usize = 4;
+
+pub struct Dict<K, V> {
+    buckets: Vec<Vec<(K, V)>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Dict::new()
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn new() -> Self {
+        Dict {
+            buckets: Self::empty_buckets(MIN_BUCKETS),
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.buckets[self.bucket_of(key)]
+            .iter()
+            .find(|(k, _)| k.borrow() == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bucket = self.bucket_of(key);
+        self.buckets[bucket]
+            .iter_mut()
+            .find(|(k, _)| k.borrow() == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Inserts or replaces `key`, returning the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(existing) = self.get_mut(&key) {
+            return Some(mem::replace(existing, value));
+        }
+
+        if self.len >= self.buckets.len() {
+            self.resize(self.buckets.len() * 2);
+        }
+        let bucket = self.bucket_of(&key);
+        self.buckets[bucket].push((key, value));
+        self.len += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bucket = self.bucket_of(key);
+        let position = self.buckets[bucket]
+            .iter()
+            .position(|(k, _)| k.borrow() == key)?;
+        let (_, value) = self.buckets[bucket].swap_remove(position);
+        self.len -= 1;
+
+        // Shrink once the table is mostly empty, like Redis' htNeedsResize
+        if self.buckets.len() > MIN_BUCKETS && self.len * 8 < self.buckets.len() {
+            self.resize(self.len.next_power_of_two().max(MIN_BUCKETS));
+        }
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets = Self::empty_buckets(MIN_BUCKETS);
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter().map(|(k, v)| (k, v)))
+    }
+
+    /// Picks an entry at random: a random non-empty bucket, then a random
+    /// entry within it. Shrinking keeps the table at least an eighth full, so
+    /// an occupied bucket turns up quickly.
+    pub fn random(&self) -> Option<(&K, &V)> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut rng = rand::rng();
+        loop {
+            let bucket = &self.buckets[rng.random_range(0..self.buckets.len())];
+            if !bucket.is_empty() {
+                let (k, v) = &bucket[rng.random_range(0..bucket.len())];
+                return Some((k, v));
+            }
+        }
+    }
+
+    /// Visits every entry of the bucket `cursor` points at and returns the
+    /// cursor of the next bucket, or 0 once the whole table was covered.
+    ///
+    /// The cursor is incremented from its most significant bit down: bucket
+    /// `i` of a table of size `n` maps to buckets `i` and `i + n` of a table
+    /// twice as large, which are then visited one after the other, so no
+    /// bucket is skipped across a resize. Shrinking may return entries twice.
+    pub fn scan(&self, cursor: u64, mut visit: impl FnMut(&K, &V)) -> u64 {
+        let mask = (self.buckets.len() - 1) as u64;
+        for (k, v) in &self.buckets[(cursor & mask) as usize] {
+            visit(k, v);
+        }
+
+        let mut cursor = cursor | !mask;
+        cursor = cursor.reverse_bits();
+        cursor = cursor.wrapping_add(1);
+        cursor.reverse_bits()
+    }
+
+    fn bucket_of<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        (self.hasher.hash_one(key) as usize) & (self.buckets.len() - 1)
+    }
+
+    fn resize(&mut self, size: usize) {
+        let old = mem::replace(&mut self.buckets, Self::empty_buckets(size));
+        for (key, value) in old.into_iter().flatten() {
+            let bucket = self.bucket_of(&key);
+            self.buckets[bucket].push((key, value));
+        }
+    }
+
+    fn empty_buckets(size: usize) -> Vec<Vec<(K, V)>> {
+        (0..size).map(|_| Vec::new()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::Dict;
+
+    fn filled(range: std::ops::Range<u32>) -> Dict<u32, ()> {
+        let mut dict = Dict::new();
+        for key in range {
+            dict.insert(key, ());
+        }
+        dict
+    }
+
+    /// Runs a full scan, calling `between` after every step.
+    fn scan_all(
+        dict: &mut Dict<u32, ()>,
+        mut between: impl FnMut(&mut Dict<u32, ()>),
+    ) -> HashSet<u32> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan(cursor, |k, _| {
+                seen.insert(*k);
+            });
+            if cursor == 0 {
+                return seen;
+            }
+            between(dict);
+        }
+    }
+
+    #[test]
+    fn scan_visits_every_key() {
+        let mut dict = filled(0..1000);
+        let seen = scan_all(&mut dict, |_| {});
+        assert_eq!(seen, (0..1000).collect());
+    }
+
+    #[test]
+    fn scan_visits_every_key_while_growing() {
+        let mut dict = filled(0..100);
+        let mut next = 100;
+        let seen = scan_all(&mut dict, |dict| {
+            for key in next..(next + 50).min(3000) {
+                dict.insert(key, ());
+            }
+            next += 50;
+        });
+        assert_eq!(dict.len(), 3000);
+        assert!((0..100).all(|key| seen.contains(&key)));
+    }
+
+    #[test]
+    fn scan_visits_every_key_while_shrinking() {
+        let mut dict = filled(0..2000);
+        let mut next = 100;
+        let seen = scan_all(&mut dict, |dict| {
+            for key in next..(next + 100).min(2000) {
+                dict.remove(&key);
+            }
+            next += 100;
+        });
+        assert!(dict.len() <= 100);
+        assert!((0..100).all(|key| seen.contains(&key)));
+    }
+}
@@ -1,60 +1,218 @@
 use std::{
-    collections::HashMap,
-    net::TcpStream,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Condvar},
+    time::SystemTime,
 };
 
 use rand::{distr::Alphanumeric, Rng};
 
+use super::{
+    glob_match, notifies, Client, Config, Database, Entry, EvictionPool, Outbox,
+    OutputBufferLimit, PubSub, ReplicationBacklog, Tracking, NOTIFY_EVICTED, NOTIFY_EXPIRED,
+    NOTIFY_GENERIC, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE, NOTIFY_NEW, NOTIFY_STRING,
+};
+use crate::{
+    aof::Aof,
+    resp2::{reply, serialization::encode_command},
+    scripting::Scripting,
+};
+
 pub struct Environment {
     role: String,
     port: u16,
+    config: Config,
     master_replid: String,
+    master_replid2: String,
     master_repl_offset: u64,
+    second_replid_offset: Option<u64>,
+    cached_master: bool,
+    master_host: Option<(String, u16)>,
+    master_link: Option<Outbox>,
+    master_link_epoch: u64,
+    repl_state: ReplState,
+    backlog: ReplicationBacklog,
     slaves: Vec<SlaveConnection>,
-    values: HashMap<String, (String, Option<SystemTime>)>,
+    pending_full_syncs: Vec<SlaveConnection>,
+    ack_signal: Arc<Condvar>,
+    dirty: u64,
+    /// Effects of the command being executed.
+    pending: Vec<Effect>,
+    /// Database last selected in the replication stream.
+    repl_selected_db: Option<usize>,
+    /// Database selected by our master's stream when the link last dropped.
+    master_db: usize,
+    aof: Option<Aof>,
+    eviction_pool: EvictionPool,
+    evicted_keys: u64,
+    /// Clients watching each key, by database and key.
+    watched_keys: HashMap<(usize, String), Vec<u64>>,
+    /// Clients one of whose watched keys was touched, so their EXEC fails.
+    dirty_cas: HashSet<u64>,
+    /// The Lua interpreter, missing only while it runs a script.
+    scripting: Option<Scripting>,
+    pubsub: PubSub,
+    tracking: Tracking,
+    /// Client whose command is being executed.
+    current_client: u64,
+    dbs: Vec<Database>,
+}
+
+/// Progress of a replica's link with its master.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReplState {
+    None,
+    Connect,
+    Connecting,
+    Handshake,
+    Transfer,
+    Connected,
+}
+
+/// A command to propagate, against the database it applies to.
+pub struct Effect {
+    pub db: usize,
+    pub args: Vec<String>,
+    /// Sent to replicas but left out of the AOF, as Redis does with
+    /// sharded Pub/Sub messages.
+    pub replicas_only: bool,
 }
 
 #[allow(dead_code)]
 pub struct SlaveConnection {
-    stream: Arc<Mutex<TcpStream>>,
+    /// Id of the replica's client.
+    id: u64,
+    outbox: Outbox,
+    addr: Option<SocketAddr>,
+    soft_limit_since: Option<SystemTime>,
+    /// Bytes queued up to the end of the full-sync snapshot, which don't
+    /// count against the output buffer limits.
+    snapshot_end: u64,
     offset: u64,
+    listening_port: u16,
+    last_ack: SystemTime,
 }
 
 #[allow(dead_code)]
 impl SlaveConnection {
-    pub fn new(stream: Arc<Mutex<TcpStream>>, offset: u64) -> Self {
-        SlaveConnection { stream, offset }
-    }
-
-    pub fn stream(&self) -> Arc<Mutex<TcpStream>> {
-        Arc::clone(&self.stream)
+    /// The replication stream is queued to `outbox`, the output of the
+    /// replica's own connection.
+    pub fn new(id: u64, outbox: Outbox, offset: u64, listening_port: u16) -> Self {
+        SlaveConnection {
+            id,
+            addr: outbox.peer_addr(),
+            outbox,
+            soft_limit_since: None,
+            snapshot_end: 0,
+            offset,
+            listening_port,
+            last_ack: SystemTime::now(),
+        }
     }
 
     pub fn offset(&self) -> u64 {
         self.offset
     }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    pub fn ip(&self) -> String {
+        self.addr.map(|addr| addr.ip().to_string()).unwrap_or_default()
+    }
+
+    pub fn listening_port(&self) -> u16 {
+        self.listening_port
+    }
+
+    /// Seconds since the replica last acknowledged its offset.
+    pub fn lag(&self) -> u64 {
+        self.last_ack.elapsed().map_or(0, |d| d.as_secs())
+    }
+
+    /// Bytes queued for the replica that were not written to its socket yet.
+    pub fn output_buffer_size(&self) -> usize {
+        self.outbox.buffered()
+    }
+
+    /// Queues data for the replica without blocking. Returns false when the
+    /// replica went away or its output buffer is over the configured limits.
+    pub fn send(&mut self, data: &[u8], limit: &OutputBufferLimit) -> bool {
+        let Some(buffered) = self.outbox.push(data) else {
+            return false;
+        };
+        // Whatever is left of the snapshot is still in the buffer, but only
+        // the stream queued after it is limited
+        let streamed = self.outbox.pushed().saturating_sub(self.snapshot_end);
+        let limited = buffered.min(usize::try_from(streamed).unwrap_or(usize::MAX));
+        !limit.exceeded(limited, &mut self.soft_limit_since)
+    }
+
+    /// Queues the snapshot of a full synchronization, however large it is.
+    /// Returns false when the replica went away.
+    pub fn send_snapshot(&mut self, data: &[u8]) -> bool {
+        if self.outbox.push(data).is_none() {
+            return false;
+        }
+        self.snapshot_end = self.outbox.pushed();
+        true
+    }
+
+    fn acknowledge(&mut self, offset: u64) {
+        self.offset = self.offset.max(offset);
+        self.last_ack = SystemTime::now();
+    }
+
+    fn disconnect(&self) {
+        self.outbox.disconnect();
+    }
 }
 
 fn generate_replid() -> String {
-    return rand::rng()
+    rand::rng()
         .sample_iter(&Alphanumeric)
         .take(40)
         .map(char::from)
-        .collect();
+        .collect()
 }
 
 #[allow(dead_code)]
 impl Environment {
-    pub fn new(role: String, port: u16) -> Self {
+    pub fn new(role: String, port: u16, config: Config) -> Self {
+        let backlog = ReplicationBacklog::new(config.repl_backlog_size, 1);
+        let dbs = (0..config.databases).map(|_| Database::new()).collect();
         Environment {
             role,
             port,
+            config,
             master_replid: generate_replid(),
+            master_replid2: "0".repeat(40),
             master_repl_offset: 0,
+            second_replid_offset: None,
+            cached_master: false,
+            master_host: None,
+            master_link: None,
+            master_link_epoch: 0,
+            repl_state: ReplState::None,
+            backlog,
             slaves: Vec::new(),
-            values: HashMap::new(),
+            pending_full_syncs: Vec::new(),
+            ack_signal: Arc::new(Condvar::new()),
+            dirty: 0,
+            pending: Vec::new(),
+            repl_selected_db: None,
+            master_db: 0,
+            aof: None,
+            eviction_pool: EvictionPool::new(),
+            evicted_keys: 0,
+            watched_keys: HashMap::new(),
+            dirty_cas: HashSet::new(),
+            scripting: Some(Scripting::new()),
+            pubsub: PubSub::new(),
+            tracking: Tracking::new(),
+            current_client: 0,
+            dbs,
         }
     }
 
@@ -82,63 +240,745 @@ impl Environment {
         self.master_repl_offset = offset;
     }
 
-    pub fn add_slave(&mut self, stream: Arc<Mutex<TcpStream>>, offset: u64) {
-        self.slaves.push(SlaveConnection { stream, offset });
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn master_replid2(&self) -> &str {
+        &self.master_replid2
+    }
+
+    pub fn second_replid_offset(&self) -> Option<u64> {
+        self.second_replid_offset
+    }
+
+    pub fn backlog(&self) -> &ReplicationBacklog {
+        &self.backlog
+    }
+
+    pub fn cached_master(&self) -> bool {
+        self.cached_master
+    }
+
+    pub fn master_host(&self) -> Option<&(String, u16)> {
+        self.master_host.as_ref()
+    }
+
+    pub fn repl_state(&self) -> ReplState {
+        self.repl_state
+    }
+
+    pub fn set_repl_state(&mut self, state: ReplState) {
+        self.repl_state = state;
+    }
+
+    /// Identifies the current master configuration; it changes every time
+    /// REPLICAOF is applied, telling older link threads to give up.
+    pub fn master_link_epoch(&self) -> u64 {
+        self.master_link_epoch
+    }
+
+    pub fn set_master_link(&mut self, link: Outbox) {
+        self.master_link = Some(link);
+    }
+
+    fn close_master_link(&mut self) {
+        if let Some(link) = self.master_link.take() {
+            link.disconnect();
+        }
+    }
+
+    /// Starts replicating from `host`, returning the epoch of the new link.
+    pub fn set_master(&mut self, host: (String, u16)) -> u64 {
+        if self.role == "master" {
+            // Our own history is the best guess for a partial resync
+            self.cached_master = true;
+        }
+        self.role = "slave".to_string();
+        self.master_host = Some(host);
+        self.master_link_epoch += 1;
+        self.repl_state = ReplState::Connect;
+        self.close_master_link();
+        self.disconnect_slaves();
+        self.master_link_epoch
+    }
+
+    /// Turns this replica into a master, keeping its replication history so
+    /// former sibling replicas can partially resync with it.
+    pub fn unset_master(&mut self) {
+        if self.role == "master" {
+            return;
+        }
+        self.role = "master".to_string();
+        self.master_host = None;
+        self.master_link_epoch += 1;
+        self.repl_state = ReplState::None;
+        self.close_master_link();
+        self.shift_replication_id();
+        // Sub-replicas reconnect and learn the new id through +CONTINUE
+        self.disconnect_slaves();
+    }
+
+    /// Moves the current replication id to the secondary slot and starts a new
+    /// history, so replicas of the old id can still partially resync with us.
+    pub fn shift_replication_id(&mut self) {
+        self.master_replid2 = std::mem::replace(&mut self.master_replid, generate_replid());
+        self.second_replid_offset = Some(self.master_repl_offset + 1);
+        self.repl_selected_db = None;
+    }
+
+    /// Adopts a new replication id announced by our master in `+CONTINUE`,
+    /// keeping the old one valid up to the current offset.
+    pub fn switch_master_replid(&mut self, replid: String) {
+        if replid == self.master_replid {
+            return;
+        }
+        self.master_replid2 = std::mem::replace(&mut self.master_replid, replid);
+        self.second_replid_offset = Some(self.master_repl_offset + 1);
+        self.disconnect_slaves();
+    }
+
+    /// Resets the replication history after a full resynchronization with our
+    /// master. Our own replicas hold a different history now and must resync.
+    pub fn reset_replication(&mut self, replid: String, offset: u64) {
+        self.disconnect_slaves();
+        self.master_replid = replid;
+        self.master_replid2 = "0".repeat(40);
+        self.second_replid_offset = None;
+        self.master_repl_offset = offset;
+        self.backlog = ReplicationBacklog::new(self.config.repl_backlog_size, offset + 1);
+        self.cached_master = true;
+        self.repl_selected_db = None;
+    }
+
+    pub fn master_db(&self) -> usize {
+        self.master_db
+    }
+
+    /// Remembers the database our master's stream has selected, so that a
+    /// partial resynchronization continues in it and a snapshot taken for our
+    /// own replicas records it.
+    pub fn set_master_db(&mut self, db: usize) {
+        self.master_db = db;
+    }
+
+    /// Makes the next propagated write start with a SELECT, as replicas that
+    /// just loaded a snapshot don't know which database the stream is in.
+    pub fn reset_repl_selected_db(&mut self) {
+        self.repl_selected_db = None;
+    }
+
+    /// Appends bytes to the replication stream: they are kept in the backlog
+    /// and advance the replication offset. Replicas feed the stream received
+    /// from their master, keeping offsets identical along a replication chain.
+    pub fn feed_replication_stream(&mut self, data: &[u8]) {
+        self.backlog.feed(data);
+        self.master_repl_offset += data.len() as u64;
+    }
+
+    /// Feeds `data` to the replication stream and forwards it to every replica.
+    pub fn propagate(&mut self, data: &[u8]) {
+        self.feed_replication_stream(data);
+
+        let limit = self.config.replica_output_buffer_limit;
+        self.slaves.retain_mut(|slave| {
+            if slave.send(data, &limit) {
+                return true;
+            }
+
+            eprintln!(
+                "Disconnecting replica {}:{}: output buffer is {} bytes",
+                slave.ip(),
+                slave.listening_port(),
+                slave.output_buffer_size()
+            );
+            slave.disconnect();
+            false
+        });
+    }
+
+    /// Checks whether a replica asking for `PSYNC <replid> <offset>` can continue
+    /// from our backlog, returning the bytes it is missing.
+    pub fn try_partial_resync(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        if replid != self.master_replid {
+            let within_history = replid == self.master_replid2
+                && self
+                    .second_replid_offset
+                    .is_some_and(|second| offset <= second);
+            if !within_history {
+                return None;
+            }
+        }
+
+        self.backlog.range_from(offset)
+    }
+
+    pub fn databases(&self) -> &[Database] {
+        &self.dbs
+    }
+
+    pub fn db(&self, db: usize) -> &Database {
+        &self.dbs[db]
+    }
+
+    /// Estimated memory used by the dataset, which `maxmemory` limits.
+    pub fn used_memory(&self) -> usize {
+        self.dbs.iter().map(Database::used_memory).sum()
+    }
+
+    pub fn eviction_pool_mut(&mut self) -> &mut EvictionPool {
+        &mut self.eviction_pool
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys
+    }
+
+    /// Deletes `key` to free memory, replicating the deletion.
+    pub fn evict(&mut self, db: usize, key: &str) {
+        if self.dbs[db].remove(key).is_some() {
+            self.signal_modified_key(db, key);
+            self.evicted_keys += 1;
+            self.notify(NOTIFY_EVICTED, "evicted", db, key);
+            self.also_propagate(db, vec!["DEL".to_string(), key.to_string()]);
+        }
+    }
+
+    pub fn flush_db(&mut self, db: usize) {
+        self.touch_watched_db(db, None);
+        self.dbs[db].clear();
+        self.invalidate_all_keys();
+        self.dirty += 1;
+    }
+
+    pub fn flush_all(&mut self) {
+        for db in 0..self.dbs.len() {
+            self.touch_watched_db(db, None);
+            self.dbs[db].clear();
+        }
+        self.invalidate_all_keys();
+        self.dirty += 1;
+    }
+
+    pub fn swap_db(&mut self, first: usize, second: usize) {
+        self.touch_watched_db(first, Some(second));
+        self.touch_watched_db(second, Some(first));
+        self.dbs.swap(first, second);
+        self.dirty += 1;
+    }
+
+    /// Moves `key` from database `src` to `dst`, unless it is missing from
+    /// `src` or already present in `dst`.
+    pub fn move_key(&mut self, src: usize, key: &str, dst: usize) -> bool {
+        if self.expire_if_needed(src, key) || self.expire_if_needed(dst, key) {
+            return false;
+        }
+        if self.dbs[dst].get(key).is_some() {
+            return false;
+        }
+
+        match self.dbs[src].remove(key) {
+            Some(entry) => {
+                self.dbs[dst].insert_entry(key.to_string(), entry);
+                self.signal_modified_key(src, key);
+                self.signal_modified_key(dst, key);
+                self.notify(NOTIFY_GENERIC, "move_from", src, key);
+                self.notify(NOTIFY_GENERIC, "move_to", dst, key);
+                self.dirty += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_slave(&mut self, slave: SlaveConnection) {
+        self.slaves.push(slave);
+    }
+
+    /// Parks a replica until the next shared full synchronization. Returns
+    /// true when it is the first one waiting, so a transfer must be scheduled.
+    pub fn queue_full_sync(&mut self, slave: SlaveConnection) -> bool {
+        self.pending_full_syncs.push(slave);
+        self.pending_full_syncs.len() == 1
+    }
+
+    pub fn take_full_syncs(&mut self) -> Vec<SlaveConnection> {
+        std::mem::take(&mut self.pending_full_syncs)
     }
 
     pub fn slaves(&self) -> &Vec<SlaveConnection> {
         &self.slaves
     }
 
-    pub fn values(&self) -> &HashMap<String, (String, Option<SystemTime>)> {
-        &self.values
+    /// Records a `REPLCONF ACK <offset>` received from replica `id`.
+    pub fn acknowledge_slave(&mut self, id: u64, offset: u64) {
+        if let Some(slave) = self.slaves.iter_mut().find(|slave| slave.id == id) {
+            slave.acknowledge(offset);
+            self.ack_signal.notify_all();
+        }
+    }
+
+    /// Condition variable notified whenever a replica acknowledges an offset;
+    /// wait on it with the guard of the `Environment` mutex.
+    pub fn ack_signal(&self) -> Arc<Condvar> {
+        Arc::clone(&self.ack_signal)
+    }
+
+    /// Number of replicas that acknowledged at least `offset`.
+    pub fn count_acknowledged(&self, offset: u64) -> usize {
+        self.slaves
+            .iter()
+            .filter(|slave| slave.offset() >= offset)
+            .count()
+    }
+
+    pub fn disconnect_slaves(&mut self) {
+        for slave in self.slaves.drain(..).chain(self.pending_full_syncs.drain(..)) {
+            slave.disconnect();
+        }
+    }
+
+    pub fn remove_slave(&mut self, id: u64) {
+        self.slaves.retain(|slave| {
+            let keep = slave.id != id;
+            if !keep {
+                slave.disconnect();
+            }
+            keep
+        });
     }
 
-    pub fn get_slave(&self, stream: &TcpStream) -> Option<&SlaveConnection> {
-        if let Ok(target_addr) = stream.peer_addr() {
-            self.slaves.iter().find(|slave| {
-                slave
-                    .stream
-                    .lock()
-                    .ok()
-                    .and_then(|s| s.peer_addr().ok())
-                    .map_or(false, |addr| addr == target_addr)
+    /// Number of changes made to the dataset since startup.
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    /// Counts a change made outside the keyspace, such as loading a library.
+    pub fn mark_dirty(&mut self) {
+        self.dirty += 1;
+    }
+
+    /// Queues a command against database `db` to be logged and replicated
+    /// once the current command completes.
+    pub fn also_propagate(&mut self, db: usize, args: Vec<String>) {
+        self.pending.push(Effect {
+            db,
+            args,
+            replicas_only: false,
+        });
+    }
+
+    /// Like [`Environment::also_propagate`], for a command the AOF has no
+    /// use for.
+    pub fn also_propagate_to_replicas(&mut self, db: usize, args: Vec<String>) {
+        self.pending.push(Effect {
+            db,
+            args,
+            replicas_only: true,
+        });
+    }
+
+    pub fn take_pending(&mut self) -> Vec<Effect> {
+        std::mem::take(&mut self.pending)
+    }
+
+    pub fn scripting(&self) -> Option<&Scripting> {
+        self.scripting.as_ref()
+    }
+
+    pub fn scripting_mut(&mut self) -> Option<&mut Scripting> {
+        self.scripting.as_mut()
+    }
+
+    pub fn take_scripting(&mut self) -> Option<Scripting> {
+        self.scripting.take()
+    }
+
+    pub fn set_scripting(&mut self, scripting: Scripting) {
+        self.scripting = Some(scripting);
+    }
+
+    /// Forgets everything about a client that disconnected.
+    pub fn disconnect_client(&mut self, client: &Client) {
+        self.pubsub.remove(client);
+        self.tracking.disable(client.id());
+    }
+
+    pub fn set_current_client(&mut self, id: u64) {
+        self.current_client = id;
+    }
+
+    pub fn tracking(&self) -> &Tracking {
+        &self.tracking
+    }
+
+    pub fn tracking_mut(&mut self) -> &mut Tracking {
+        &mut self.tracking
+    }
+
+    pub fn pubsub(&self) -> &PubSub {
+        &self.pubsub
+    }
+
+    pub fn pubsub_mut(&mut self) -> &mut PubSub {
+        &mut self.pubsub
+    }
+
+    /// Publishes `message` to `channel`, returning the number of receivers.
+    pub fn publish(&mut self, channel: &str, message: &str) -> usize {
+        self.pubsub.publish(channel, message, &self.config)
+    }
+
+    /// Publishes a keyspace notification of `class` about `key`, provided
+    /// `notify-keyspace-events` enables that class.
+    pub fn notify(&mut self, class: u32, event: &str, db: usize, key: &str) {
+        let flags = self.config.notify_keyspace_events;
+        if !notifies(flags, class) {
+            return;
+        }
+
+        if flags & NOTIFY_KEYSPACE != 0 {
+            self.publish(&format!("__keyspace@{}__:{}", db, key), event);
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            self.publish(&format!("__keyevent@{}__:{}", db, event), key);
+        }
+    }
+
+    /// Publishes `message` to shard channel `channel`, returning the number
+    /// of receivers.
+    pub fn spublish(&mut self, channel: &str, message: &str) -> usize {
+        self.pubsub.spublish(channel, message, &self.config)
+    }
+
+    pub fn aof(&self) -> Option<&Aof> {
+        self.aof.as_ref()
+    }
+
+    pub fn aof_mut(&mut self) -> Option<&mut Aof> {
+        self.aof.as_mut()
+    }
+
+    pub fn set_aof(&mut self, aof: Aof) {
+        self.aof = Some(aof);
+    }
+
+    /// Appends the effects of a command to the append-only file, if enabled.
+    pub fn feed_aof(&mut self, effects: &[Effect]) {
+        let effects: Vec<&Effect> = effects
+            .iter()
+            .filter(|effect| !effect.replicas_only)
+            .collect();
+        if effects.is_empty() {
+            return;
+        }
+
+        if let Some(aof) = self.aof.as_mut() {
+            let mut selected = aof.selected_db();
+            let payload = encode_effects(&effects, &mut selected);
+            aof.set_selected_db(selected);
+
+            if let Err(e) = aof.append(&payload) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    /// Encodes the effects of a command for the replication stream and
+    /// forwards them to every replica.
+    pub fn propagate_effects(&mut self, effects: &[Effect]) {
+        let effects: Vec<&Effect> = effects.iter().collect();
+        let mut selected = self.repl_selected_db;
+        let payload = encode_effects(&effects, &mut selected);
+        self.repl_selected_db = selected;
+        self.propagate(&payload);
+    }
+
+    pub fn set(&mut self, db: usize, key: String, value: String, expiry: Option<SystemTime>) {
+        self.signal_modified_key(db, &key);
+        let created = self.dbs[db].get(&key).is_none();
+        self.dbs[db].insert(key.clone(), value, expiry);
+        self.dirty += 1;
+
+        if created {
+            self.notify(NOTIFY_NEW, "new", db, &key);
+        }
+        self.notify(NOTIFY_STRING, "set", db, &key);
+    }
+
+    /// Stores a key read from a snapshot. Loading is not a write by any
+    /// client, so nothing is notified, invalidated or counted as dirty.
+    pub fn insert_loaded(
+        &mut self,
+        db: usize,
+        key: String,
+        value: String,
+        expiry: Option<SystemTime>,
+    ) {
+        self.dbs[db].insert(key, value, expiry);
+    }
+
+    pub fn get(&mut self, db: usize, key: &str) -> Option<&str> {
+        if self.expire_if_needed(db, key) {
+            return None;
+        }
+
+        let (log_factor, decay_time) = (self.config.lfu_log_factor, self.config.lfu_decay_time);
+        let entry = self.dbs[db].get_mut(key)?;
+        entry.touch(log_factor, decay_time);
+        Some(entry.value.as_str())
+    }
+
+    pub fn expiry(&mut self, db: usize, key: &str) -> Option<SystemTime> {
+        if self.expire_if_needed(db, key) {
+            return None;
+        }
+
+        self.dbs[db].get(key).and_then(|entry| entry.expiry)
+    }
+
+    pub fn del(&mut self, db: usize, key: &str) -> bool {
+        // Replicas keep expired keys until told otherwise, so a DEL from the
+        // master must still remove one even though it no longer counts
+        let expired = self.expire_if_needed(db, key);
+
+        let removed = self.dbs[db].remove(key).is_some();
+        if removed {
+            self.signal_modified_key(db, key);
+            self.dirty += 1;
+            self.notify(NOTIFY_GENERIC, "del", db, key);
+        }
+        removed && !expired
+    }
+
+    pub fn exists(&mut self, db: usize, key: &str) -> bool {
+        !self.expire_if_needed(db, key) && self.dbs[db].get(key).is_some()
+    }
+
+    /// Name of the type of the value stored at `key`, as reported by TYPE.
+    pub fn key_type(&mut self, db: usize, key: &str) -> Option<&'static str> {
+        self.exists(db, key).then_some("string")
+    }
+
+    /// Looks `key` up without counting it as an access, for introspection.
+    pub fn entry(&mut self, db: usize, key: &str) -> Option<&Entry> {
+        if self.expire_if_needed(db, key) {
+            return None;
+        }
+        self.dbs[db].get(key)
+    }
+
+    /// A random key of database `db`. Masters delete the expired keys they
+    /// come across; replicas may return one after enough unlucky picks.
+    pub fn random_key(&mut self, db: usize) -> Option<String> {
+        let mut tries = 0;
+        loop {
+            let key = self.dbs[db].random()?.0.clone();
+            tries += 1;
+            if !self.expire_if_needed(db, &key) || (self.role != "master" && tries >= 100) {
+                return Some(key);
+            }
+        }
+    }
+
+    /// Keys of database `db` matching the glob `pattern`. Logically expired
+    /// keys are skipped but left for their owner to delete.
+    pub fn keys(&self, db: usize, pattern: &str) -> Vec<String> {
+        let now = SystemTime::now();
+        self.dbs[db]
+            .values()
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .filter(|(key, _)| {
+                pattern == "*" || glob_match(pattern.as_bytes(), key.as_bytes(), false)
             })
-        } else {
-            None
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Runs SCAN steps from `cursor` until about `count` keys were collected,
+    /// returning the cursor to resume from (0 when done) and the keys. Like
+    /// Redis, gives up after visiting ten times `count` buckets.
+    pub fn scan(&self, db: usize, mut cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let mut keys = Vec::new();
+        let mut budget = count.saturating_mul(10);
+
+        loop {
+            cursor = self.dbs[db].scan(cursor, |key, _| keys.push(key.clone()));
+            budget -= 1;
+            if cursor == 0 || keys.len() >= count || budget == 0 {
+                return (cursor, keys);
+            }
         }
     }
 
-    pub fn remove_slave(&mut self, stream: &TcpStream) {
-        if let Ok(addr) = stream.peer_addr() {
-            self.slaves.retain(|slave| {
-                slave
-                    .stream
-                    .lock()
-                    .ok()
-                    .and_then(|s| s.peer_addr().ok())
-                    .map_or(true, |a| a != addr)
-            });
+    /// Registers client `id` as watching `key`. An expired key is deleted
+    /// first, so only expiring after WATCH invalidates the transaction.
+    pub fn watch(&mut self, id: u64, db: usize, key: &str) {
+        self.expire_if_needed(db, key);
+        self.watched_keys
+            .entry((db, key.to_string()))
+            .or_default()
+            .push(id);
+    }
+
+    /// Forgets the `keys` client `id` watched, along with whether they were touched.
+    pub fn unwatch(&mut self, id: u64, keys: Vec<(usize, String)>) {
+        for key in keys {
+            if let Some(watchers) = self.watched_keys.get_mut(&key) {
+                watchers.retain(|&watcher| watcher != id);
+                if watchers.is_empty() {
+                    self.watched_keys.remove(&key);
+                }
+            }
         }
+        self.dirty_cas.remove(&id);
     }
 
-    pub fn set(&mut self, key: String, value: String, px: Option<u64>) {
-        let expiry = px.map(|ms| SystemTime::now() + Duration::from_millis(ms));
-        self.values.insert(key, (value, expiry));
+    /// Whether one of the `keys` client `id` watched was modified or has
+    /// expired since, which makes its EXEC fail.
+    pub fn watch_invalidated(&self, id: u64, keys: &[(usize, String)]) -> bool {
+        let now = SystemTime::now();
+        self.dirty_cas.contains(&id)
+            || keys.iter().any(|(db, key)| {
+                self.dbs[*db]
+                    .get(key)
+                    .is_some_and(|entry| entry.is_expired(now))
+            })
     }
 
-    pub fn get(&mut self, key: &str) -> Option<&str> {
-        let expired = self
-            .values
+    /// Called whenever `key` changes: fails the transactions watching it and
+    /// tells the clients that may have cached it.
+    fn signal_modified_key(&mut self, db: usize, key: &str) {
+        self.touch_watched_key(db, key);
+        for id in self.tracking.invalidate(key) {
+            self.send_invalidation(id, reply::array(vec![reply::bulk(key)]));
+        }
+    }
+
+    /// Tells every tracking client that all the keys it cached are gone.
+    fn invalidate_all_keys(&mut self) {
+        for id in self.tracking.flush() {
+            self.send_invalidation(id, reply::null());
+        }
+    }
+
+    /// Sends an invalidation message for `keys` to tracking client `id`, or
+    /// to the client it redirects to. Only RESP3 connections can receive
+    /// pushes among their replies; RESP2 ones must redirect to a client in
+    /// subscribed mode, which gets them as `__redis__:invalidate` messages.
+    fn send_invalidation(&mut self, id: u64, keys: Vec<u8>) {
+        let Some(options) = self.tracking.options(id) else {
+            return;
+        };
+        if options.noloop && id == self.current_client {
+            return;
+        }
+        let redirect = options.redirect;
+
+        if redirect == 0 {
+            if self.pubsub.is_resp3(id) == Some(true) {
+                let items = vec![reply::bulk("invalidate"), keys];
+                self.pubsub.push(id, items, &self.config);
+            }
+            return;
+        }
+
+        if !Client::is_connected(redirect) {
+            self.tracking.mark_broken_redirect(id);
+            if self.pubsub.is_resp3(id) == Some(true) {
+                let items = vec![
+                    reply::bulk("tracking-redir-broken"),
+                    reply::integer(redirect as i64),
+                ];
+                self.pubsub.push(id, items, &self.config);
+            }
+            return;
+        }
+
+        match self.pubsub.is_resp3(redirect) {
+            Some(true) => {
+                let items = vec![reply::bulk("invalidate"), keys];
+                self.pubsub.push(redirect, items, &self.config);
+            }
+            Some(false) if self.pubsub.is_subscribed(redirect) => {
+                let items = vec![
+                    reply::bulk("message"),
+                    reply::bulk("__redis__:invalidate"),
+                    keys,
+                ];
+                self.pubsub.push(redirect, items, &self.config);
+            }
+            _ => {}
+        }
+    }
+
+    /// Invalidates the transactions of the clients watching `key`.
+    fn touch_watched_key(&mut self, db: usize, key: &str) {
+        if self.watched_keys.is_empty() {
+            return;
+        }
+        if let Some(watchers) = self.watched_keys.get(&(db, key.to_string())) {
+            self.dirty_cas.extend(watchers);
+        }
+    }
+
+    /// Invalidates the transactions watching a key of `db` that is about to
+    /// be flushed or, with SWAPDB, replaced by the contents of `other`.
+    fn touch_watched_db(&mut self, db: usize, other: Option<usize>) {
+        let dbs = &self.dbs;
+        for ((watched_db, key), watchers) in &self.watched_keys {
+            let present = |index: usize| dbs[index].get(key).is_some();
+            if *watched_db == db && (present(db) || other.is_some_and(present)) {
+                self.dirty_cas.extend(watchers);
+            }
+        }
+    }
+
+    /// Checks whether `key` is logically expired. Masters delete it and
+    /// replicate the deletion; replicas keep it until their master's DEL arrives.
+    fn expire_if_needed(&mut self, db: usize, key: &str) -> bool {
+        let expired = self.dbs[db]
             .get(key)
-            .and_then(|(_, exp)| *exp)
-            .map_or(false, |expiry| SystemTime::now() >= expiry);
+            .is_some_and(|entry| entry.is_expired(SystemTime::now()));
 
-        if expired {
-            self.values.remove(key);
-            return None;
+        if expired && self.role == "master" {
+            self.dbs[db].remove(key);
+            self.signal_modified_key(db, key);
+            self.dirty += 1;
+            self.notify(NOTIFY_EXPIRED, "expired", db, key);
+            self.also_propagate(db, vec!["DEL".to_string(), key.to_string()]);
         }
 
-        self.values.get(key).map(|(val, _)| val.as_str())
+        expired
     }
 }
+
+/// Encodes the effects of one command, wrapped in a transaction when there are
+/// several of them. A SELECT is emitted whenever an effect targets another
+/// database than the one `selected` in the stream being written.
+fn encode_effects(effects: &[&Effect], selected: &mut Option<usize>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let mut select = |db: usize, payload: &mut Vec<u8>| {
+        if *selected != Some(db) {
+            payload.extend(encode_command(&["SELECT".to_string(), db.to_string()]));
+            *selected = Some(db);
+        }
+    };
+
+    let wrap = effects.len() > 1;
+    if wrap {
+        // Switch before MULTI so the transaction starts in the right database
+        select(effects[0].db, &mut payload);
+        payload.extend(encode_command(&["MULTI".to_string()]));
+    }
+    for effect in effects {
+        select(effect.db, &mut payload);
+        payload.extend(encode_command(&effect.args));
+    }
+    if wrap {
+        payload.extend(encode_command(&["EXEC".to_string()]));
+    }
+
+    payload
+}
@@ -0,0 +1,176 @@
+//! Key eviction once the dataset outgrows `maxmemory`, following Redis'
+//! approximated algorithms: instead of keeping every key ordered by access,
+//! a few keys are sampled per round and the best candidates seen so far are
+//! kept in a small pool across rounds.
+
+use std::time::UNIX_EPOCH;
+
+use super::{Database, Entry, Environment, MaxmemoryPolicy};
+
+const POOL_SIZE: usize = 16;
+
+struct Candidate {
+    /// Higher is a better candidate: idle time, inverse frequency or
+    /// closeness to expiring, depending on the policy.
+    score: u64,
+    db: usize,
+    key: String,
+}
+
+#[derive(Default)]
+pub struct EvictionPool {
+    /// Sorted by ascending score, the best candidate last.
+    candidates: Vec<Candidate>,
+    /// Database the random policies pick from next, to spread evictions.
+    next_db: usize,
+}
+
+impl EvictionPool {
+    pub fn new() -> Self {
+        EvictionPool::default()
+    }
+
+    fn insert(&mut self, candidate: Candidate) {
+        if let Some(existing) = self
+            .candidates
+            .iter_mut()
+            .find(|c| c.db == candidate.db && c.key == candidate.key)
+        {
+            existing.score = candidate.score;
+        } else {
+            self.candidates.push(candidate);
+        }
+
+        self.candidates.sort_by_key(|c| c.score);
+        if self.candidates.len() > POOL_SIZE {
+            self.candidates.remove(0);
+        }
+    }
+}
+
+/// Evicts keys until the dataset fits in `maxmemory`. Returns false when that
+/// is impossible, either because the policy forbids evicting or because no
+/// key is eligible.
+pub fn perform_evictions(env: &mut Environment) -> bool {
+    let maxmemory = env.config().maxmemory;
+    if maxmemory == 0 {
+        return true;
+    }
+
+    while env.used_memory() > maxmemory {
+        let policy = env.config().maxmemory_policy;
+        let victim = match policy {
+            MaxmemoryPolicy::NoEviction => return false,
+            MaxmemoryPolicy::AllKeysRandom | MaxmemoryPolicy::VolatileRandom => {
+                pick_random(env, policy == MaxmemoryPolicy::VolatileRandom)
+            }
+            _ => pick_from_pool(env, policy),
+        };
+
+        match victim {
+            Some((db, key)) => env.evict(db, &key),
+            None => return false,
+        }
+    }
+
+    true
+}
+
+fn is_volatile_policy(policy: MaxmemoryPolicy) -> bool {
+    matches!(
+        policy,
+        MaxmemoryPolicy::VolatileLru
+            | MaxmemoryPolicy::VolatileLfu
+            | MaxmemoryPolicy::VolatileRandom
+            | MaxmemoryPolicy::VolatileTtl
+    )
+}
+
+/// Samples `count` eligible keys of `db`. Keys are drawn at random; when a
+/// volatile policy finds none that way, the few volatile keys are searched for.
+fn sample(db: &Database, count: usize, volatile: bool) -> Vec<(&String, &Entry)> {
+    if (volatile && db.expires() == 0) || db.is_empty() {
+        return Vec::new();
+    }
+
+    let mut samples: Vec<(&String, &Entry)> = (0..count)
+        .filter_map(|_| db.random())
+        .filter(|(_, entry)| !volatile || entry.expiry.is_some())
+        .collect();
+
+    if samples.is_empty() && volatile {
+        samples = db
+            .values()
+            .iter()
+            .filter(|(_, entry)| entry.expiry.is_some())
+            .take(count)
+            .collect();
+    }
+    samples
+}
+
+fn pick_from_pool(env: &mut Environment, policy: MaxmemoryPolicy) -> Option<(usize, String)> {
+    let count = env.config().maxmemory_samples;
+    let decay_time = env.config().lfu_decay_time;
+    let volatile = is_volatile_policy(policy);
+
+    loop {
+        let mut found = Vec::new();
+        for (index, db) in env.databases().iter().enumerate() {
+            for (key, entry) in sample(db, count, volatile) {
+                let score = match policy {
+                    MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                        (u8::MAX - entry.frequency(decay_time)) as u64
+                    }
+                    MaxmemoryPolicy::VolatileTtl => {
+                        let expires_at = entry
+                            .expiry
+                            .and_then(|expiry| expiry.duration_since(UNIX_EPOCH).ok())
+                            .map_or(0, |d| d.as_millis() as u64);
+                        u64::MAX - expires_at
+                    }
+                    _ => entry.idle_time().as_millis() as u64,
+                };
+                found.push(Candidate {
+                    score,
+                    db: index,
+                    key: key.clone(),
+                });
+            }
+        }
+        if found.is_empty() {
+            return None;
+        }
+
+        for candidate in found {
+            env.eviction_pool_mut().insert(candidate);
+        }
+
+        // The pool may hold keys deleted since they were sampled
+        while let Some(candidate) = env.eviction_pool_mut().candidates.pop() {
+            let eligible = env
+                .db(candidate.db)
+                .get(&candidate.key)
+                .is_some_and(|entry| !volatile || entry.expiry.is_some());
+            if eligible {
+                return Some((candidate.db, candidate.key));
+            }
+        }
+    }
+}
+
+fn pick_random(env: &mut Environment, volatile: bool) -> Option<(usize, String)> {
+    let dbs = env.databases().len();
+    let start = env.eviction_pool_mut().next_db;
+
+    for i in 0..dbs {
+        let index = (start + i) % dbs;
+        if let Some((key, _)) = sample(env.db(index), 1, volatile).first() {
+            let key = key.to_string();
+            env.eviction_pool_mut().next_db = index + 1;
+            return Some((index, key));
+        }
+    }
+    None
+}
+
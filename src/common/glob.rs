@@ -0,0 +1,173 @@
+//! Glob-style pattern matching with the semantics of Redis' `stringmatchlen`,
+//! used by KEYS and the MATCH option of the SCAN family.
+
+/// Matches `string` against `pattern`, which supports `*`, `?`, character
+/// classes such as `[abc]`, `[a-z]` and `[^x]`, and `\` to escape any of them.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    match_from(pattern, string, nocase, 0)
+}
+
+fn match_from(mut pattern: &[u8], mut string: &[u8], nocase: bool, depth: usize) -> bool {
+    // Bounds the recursion on patterns made of many stars
+    if depth > 1000 {
+        return false;
+    }
+
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+
+    while !pattern.is_empty() && !string.is_empty() {
+        match pattern[0] {
+            b'*' => {
+                while pattern.len() > 1 && pattern[1] == b'*' {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                for start in 0..string.len() {
+                    if match_from(&pattern[1..], &string[start..], nocase, depth + 1) {
+                        return true;
+                    }
+                }
+                return false;
+            }
+            b'?' => {}
+            b'[' => {
+                let (matched, rest) = match_class(&pattern[1..], string[0], nocase);
+                if !matched {
+                    return false;
+                }
+                pattern = rest;
+                string = &string[1..];
+                continue;
+            }
+            b'\\' if pattern.len() >= 2 => {
+                pattern = &pattern[1..];
+                if !eq(pattern[0], string[0]) {
+                    return false;
+                }
+            }
+            literal => {
+                if !eq(literal, string[0]) {
+                    return false;
+                }
+            }
+        }
+        pattern = &pattern[1..];
+        string = &string[1..];
+    }
+
+    string.is_empty() && pattern.iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class whose body starts at `pattern` (just past
+/// the `[`), returning the outcome and the pattern following the `]`.
+fn match_class(mut pattern: &[u8], c: u8, nocase: bool) -> (bool, &[u8]) {
+    let fold = |b: u8| if nocase { b.to_ascii_lowercase() } else { b };
+    let c = fold(c);
+
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern {
+            // An unterminated class ends with the pattern, as in Redis
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= fold(*escaped) == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if fold(*start) <= fold(*end) {
+                    (fold(*start), fold(*end))
+                } else {
+                    (fold(*end), fold(*start))
+                };
+                matched |= (low..=high).contains(&c);
+                pattern = rest;
+            }
+            [literal, rest @ ..] => {
+                matched |= fold(*literal) == c;
+                pattern = rest;
+            }
+        }
+    }
+
+    (matched != negate, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn stars_and_question_marks() {
+        assert!(matches("*", ""));
+        assert!(matches("h*llo", "heeello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("a**b*", "axxbyy"));
+        assert!(!matches("a*b", "axxc"));
+    }
+
+    #[test]
+    fn classes_and_ranges() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("key:[0-9]", "key:7"));
+        assert!(!matches("key:[0-9]", "key:x"));
+        // Reversed bounds are swapped, as in Redis
+        assert!(matches("[z-a]", "m"));
+        // A trailing dash is a literal
+        assert!(matches("[a-]", "-"));
+    }
+
+    #[test]
+    fn negated_classes() {
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(!matches("[^a-c]", "b"));
+        assert!(matches("[^a-c]", "d"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(r"a\*b", "a*b"));
+        assert!(!matches(r"a\*b", "axb"));
+        assert!(matches(r"\?", "?"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(matches(r"[\^a]", "^"));
+    }
+
+    #[test]
+    fn unterminated_class_ends_with_the_pattern() {
+        assert!(matches("a[bc", "ab"));
+        assert!(!matches("a[bc", "ad"));
+    }
+
+    #[test]
+    fn nocase() {
+        assert!(glob_match(b"HeLLo", b"hello", true));
+        assert!(glob_match(b"[A-C]x", b"bX", true));
+        assert!(glob_match(b"[^A-C]", b"d", true));
+        assert!(!glob_match(b"[^A-C]", b"b", true));
+        assert!(!glob_match(b"HeLLo", b"hello", false));
+    }
+}
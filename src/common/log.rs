@@ -0,0 +1,46 @@
+//! The server log. Like Redis' `serverLog`, messages less important than the
+//! `loglevel` set at startup are dropped.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        Ok(match value.to_lowercase().as_str() {
+            "debug" => LogLevel::Debug,
+            "verbose" => LogLevel::Verbose,
+            "notice" => LogLevel::Notice,
+            "warning" => LogLevel::Warning,
+            _ => return Err(format!("Invalid loglevel: '{}'", value)),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+        }
+    }
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(LogLevel::Notice as u8);
+
+pub fn set_verbosity(level: LogLevel) {
+    VERBOSITY.store(level as u8, Ordering::Relaxed);
+}
+
+/// Writes `message` to the log unless `level` is filtered out.
+pub fn log(level: LogLevel, message: &str) {
+    if level as u8 >= VERBOSITY.load(Ordering::Relaxed) {
+        println!("{}", message);
+    }
+}
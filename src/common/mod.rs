@@ -1,3 +1,27 @@
+mod backlog;
+mod client;
+mod config;
+mod connection;
+mod database;
+mod dict;
 mod environment;
+mod evict;
+mod glob;
+mod log;
+mod notify;
+mod pubsub;
+mod tracking;
 
+pub use backlog::*;
+pub use client::*;
+pub use config::*;
+pub use connection::*;
+pub use database::*;
+pub use dict::*;
 pub use environment::*;
+pub use evict::*;
+pub use glob::*;
+pub use log::*;
+pub use notify::*;
+pub use pubsub::*;
+pub use tracking::*;
@@ -0,0 +1,154 @@
+//! Keyspace notifications: Pub/Sub messages announcing changes to keys,
+//! enabled per class of event with `notify-keyspace-events`.
+
+/// Publish to `__keyspace@<db>__:<key>` channels, with the event as message.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0;
+/// Publish to `__keyevent@<db>__:<event>` channels, with the key as message.
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1;
+/// Commands working on keys of any type: DEL, MOVE, EXPIRE...
+pub const NOTIFY_GENERIC: u32 = 1 << 2;
+pub const NOTIFY_STRING: u32 = 1 << 3;
+// Lists, sets, hashes and sorted sets don't exist yet, so their classes are
+// accepted for compatibility with existing configurations but never fire.
+pub const NOTIFY_LIST: u32 = 1 << 4;
+pub const NOTIFY_SET: u32 = 1 << 5;
+pub const NOTIFY_HASH: u32 = 1 << 6;
+pub const NOTIFY_ZSET: u32 = 1 << 7;
+pub const NOTIFY_EXPIRED: u32 = 1 << 8;
+pub const NOTIFY_EVICTED: u32 = 1 << 9;
+pub const NOTIFY_STREAM: u32 = 1 << 10;
+/// Reads of missing keys. Not part of `A`, as it fires on every cache miss.
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11;
+pub const NOTIFY_MODULE: u32 = 1 << 12;
+/// Creation of a key. Not part of `A` either.
+pub const NOTIFY_NEW: u32 = 1 << 13;
+
+/// Every class the `A` alias stands for.
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE;
+
+const CLASSES: [(char, u32); 15] = [
+    ('A', NOTIFY_ALL),
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('K', NOTIFY_KEYSPACE),
+    ('E', NOTIFY_KEYEVENT),
+    ('t', NOTIFY_STREAM),
+    ('m', NOTIFY_KEY_MISS),
+    ('d', NOTIFY_MODULE),
+    ('n', NOTIFY_NEW),
+];
+
+/// Whether events of `class` are published under `flags`. Nothing is unless
+/// `K` or `E` picks the channels to publish to.
+pub fn notifies(flags: u32, class: u32) -> bool {
+    flags & class != 0 && flags & (NOTIFY_KEYSPACE | NOTIFY_KEYEVENT) != 0
+}
+
+/// Parses a `notify-keyspace-events` value such as `KEA` or `Kx`.
+pub fn parse_keyspace_events(value: &str) -> Result<u32, String> {
+    value.chars().try_fold(0, |flags, c| {
+        CLASSES
+            .iter()
+            .find(|(class, _)| *class == c)
+            .map(|(_, flag)| flags | flag)
+            .ok_or_else(|| "Invalid event class character. Use 'Ag$lshzxeKEtmdn'".to_string())
+    })
+}
+
+/// Formats `flags` back into a `notify-keyspace-events` value the way Redis
+/// shows it, with `A` standing in for every class it covers.
+pub fn keyspace_events_to_string(flags: u32) -> String {
+    let mut out = String::new();
+    let mut covered = 0;
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        out.push('A');
+        covered = NOTIFY_ALL;
+    }
+    for (class, flag) in &CLASSES[1..] {
+        if flags & flag != 0 && covered & flag == 0 {
+            out.push(*class);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Config;
+
+    #[test]
+    fn a_stands_for_every_class_but_misses_and_new_keys() {
+        let flags = parse_keyspace_events("A").unwrap();
+        assert_eq!(flags, NOTIFY_ALL);
+        for class in [
+            NOTIFY_GENERIC,
+            NOTIFY_STRING,
+            NOTIFY_EXPIRED,
+            NOTIFY_EVICTED,
+        ] {
+            assert_ne!(flags & class, 0);
+        }
+        assert_eq!(flags & (NOTIFY_KEY_MISS | NOTIFY_NEW), 0);
+        assert_eq!(flags & (NOTIFY_KEYSPACE | NOTIFY_KEYEVENT), 0);
+    }
+
+    #[test]
+    fn nothing_fires_without_keyspace_or_keyevent() {
+        let class_only = parse_keyspace_events("A").unwrap();
+        assert!(!notifies(class_only, NOTIFY_STRING));
+
+        let keyspace = parse_keyspace_events("K$").unwrap();
+        assert!(notifies(keyspace, NOTIFY_STRING));
+        assert!(!notifies(keyspace, NOTIFY_GENERIC));
+
+        let keyevent = parse_keyspace_events("Ex").unwrap();
+        assert!(notifies(keyevent, NOTIFY_EXPIRED));
+        assert!(!notifies(
+            parse_keyspace_events("").unwrap(),
+            NOTIFY_EXPIRED
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_classes() {
+        assert!(parse_keyspace_events("KEq").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_config_get() {
+        let cases = [
+            ("", ""),
+            ("KEA", "AKE"),
+            ("Ag$lshzxetd", "A"),
+            ("Kgx", "gxK"),
+            ("E$mn", "$Emn"),
+            ("AKEmn", "AKEmn"),
+        ];
+        let mut config = Config::default();
+        for (value, shown) in cases {
+            config.set("notify-keyspace-events", value).unwrap();
+            let got = config.get("notify-keyspace-events").unwrap();
+            assert_eq!(got, shown);
+
+            let flags = config.notify_keyspace_events;
+            config.set("notify-keyspace-events", &got).unwrap();
+            assert_eq!(config.notify_keyspace_events, flags);
+        }
+    }
+}
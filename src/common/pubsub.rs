@@ -0,0 +1,227 @@
+//! Pub/Sub: the channels and patterns connections subscribe to. Messages are
+//! queued to each subscriber's outbox, so a slow subscriber never holds up
+//! the publisher.
+
+use std::{collections::HashMap, time::SystemTime};
+
+use super::{glob_match, Client, Config, Outbox};
+use crate::resp2::reply;
+
+/// What a connection subscribes to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Subscription {
+    Channel,
+    Pattern,
+    /// A sharded channel, routed by hash slot in a cluster. Standalone, these
+    /// are plain channels in a namespace of their own.
+    Shard,
+}
+
+impl Subscription {
+    /// Kind of the reply confirming a subscription.
+    pub fn subscribe_name(self) -> &'static str {
+        match self {
+            Subscription::Channel => "subscribe",
+            Subscription::Pattern => "psubscribe",
+            Subscription::Shard => "ssubscribe",
+        }
+    }
+
+    pub fn unsubscribe_name(self) -> &'static str {
+        match self {
+            Subscription::Channel => "unsubscribe",
+            Subscription::Pattern => "punsubscribe",
+            Subscription::Shard => "sunsubscribe",
+        }
+    }
+}
+
+/// A connection messages can be pushed to.
+struct Subscriber {
+    outbox: Outbox,
+    /// Whether it speaks RESP3, which frames messages as pushes.
+    resp3: bool,
+    subscriptions: usize,
+    soft_limit_since: Option<SystemTime>,
+}
+
+/// Subscribers of every channel and pattern, by client id. Connections
+/// receiving other pushed messages, such as RESP3 clients tracking keys, are
+/// registered here too.
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<String, Vec<u64>>,
+    patterns: HashMap<String, Vec<u64>>,
+    shard_channels: HashMap<String, Vec<u64>>,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub::default()
+    }
+
+    fn names(&self, kind: Subscription) -> &HashMap<String, Vec<u64>> {
+        match kind {
+            Subscription::Channel => &self.channels,
+            Subscription::Pattern => &self.patterns,
+            Subscription::Shard => &self.shard_channels,
+        }
+    }
+
+    fn names_mut(&mut self, kind: Subscription) -> &mut HashMap<String, Vec<u64>> {
+        match kind {
+            Subscription::Channel => &mut self.channels,
+            Subscription::Pattern => &mut self.patterns,
+            Subscription::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Registers the outbox messages for client `id` are queued to.
+    pub fn attach(&mut self, id: u64, outbox: &Outbox, resp3: bool) {
+        self.subscribers
+            .entry(id)
+            .or_insert_with(|| Subscriber {
+                outbox: outbox.clone(),
+                resp3,
+                subscriptions: 0,
+                soft_limit_since: None,
+            })
+            .resp3 = resp3;
+    }
+
+    /// Subscribes client `id`, which must be attached.
+    pub fn subscribe(&mut self, kind: Subscription, id: u64, name: &str) {
+        if let Some(subscriber) = self.subscribers.get_mut(&id) {
+            subscriber.subscriptions += 1;
+            self.names_mut(kind)
+                .entry(name.to_string())
+                .or_default()
+                .push(id);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, kind: Subscription, id: u64, name: &str) {
+        let names = self.names_mut(kind);
+        if let Some(ids) = names.get_mut(name) {
+            ids.retain(|subscriber| *subscriber != id);
+            if ids.is_empty() {
+                names.remove(name);
+            }
+        }
+        if let Some(subscriber) = self.subscribers.get_mut(&id) {
+            subscriber.subscriptions = subscriber.subscriptions.saturating_sub(1);
+        }
+    }
+
+    /// Whether client `id` speaks RESP3, or None if nothing can be pushed to it.
+    pub fn is_resp3(&self, id: u64) -> Option<bool> {
+        self.subscribers.get(&id).map(|subscriber| subscriber.resp3)
+    }
+
+    /// Whether client `id` is subscribed to anything.
+    pub fn is_subscribed(&self, id: u64) -> bool {
+        self.subscribers
+            .get(&id)
+            .is_some_and(|subscriber| subscriber.subscriptions > 0)
+    }
+
+    /// Forgets a client that disconnected, with all its subscriptions.
+    pub fn remove(&mut self, client: &Client) {
+        for kind in [
+            Subscription::Channel,
+            Subscription::Pattern,
+            Subscription::Shard,
+        ] {
+            for name in client.subscriptions(kind) {
+                self.unsubscribe(kind, client.id(), name);
+            }
+        }
+        self.subscribers.remove(&client.id());
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of the patterns
+    /// matching it, returning how many received it.
+    pub fn publish(&mut self, channel: &str, message: &str, config: &Config) -> usize {
+        let mut deliveries = Vec::new();
+        if let Some(ids) = self.channels.get(channel) {
+            let items = vec![
+                reply::bulk("message"),
+                reply::bulk(channel),
+                reply::bulk(message),
+            ];
+            deliveries.extend(ids.iter().map(|id| (*id, items.clone())));
+        }
+        for (pattern, ids) in &self.patterns {
+            if glob_match(pattern.as_bytes(), channel.as_bytes(), false) {
+                let items = vec![
+                    reply::bulk("pmessage"),
+                    reply::bulk(pattern),
+                    reply::bulk(channel),
+                    reply::bulk(message),
+                ];
+                deliveries.extend(ids.iter().map(|id| (*id, items.clone())));
+            }
+        }
+
+        let receivers = deliveries.len();
+        for (id, items) in deliveries {
+            self.push(id, items, config);
+        }
+        receivers
+    }
+
+    /// Sends `message` to the subscribers of shard channel `channel`,
+    /// returning how many received it.
+    pub fn spublish(&mut self, channel: &str, message: &str, config: &Config) -> usize {
+        let items = vec![
+            reply::bulk("smessage"),
+            reply::bulk(channel),
+            reply::bulk(message),
+        ];
+        let ids = self
+            .shard_channels
+            .get(channel)
+            .cloned()
+            .unwrap_or_default();
+        for id in &ids {
+            self.push(*id, items.clone(), config);
+        }
+        ids.len()
+    }
+
+    /// Queues a message made of `items` for client `id`, disconnecting it
+    /// once its output buffer grows past the limits of its class. Returns
+    /// false if client `id` cannot receive messages.
+    pub fn push(&mut self, id: u64, items: Vec<Vec<u8>>, config: &Config) -> bool {
+        let Some(subscriber) = self.subscribers.get_mut(&id) else {
+            return false;
+        };
+        let limit = config.client_output_buffer_limit(subscriber.subscriptions > 0);
+        let payload = match subscriber.resp3 {
+            true => reply::push(items),
+            false => reply::array(items),
+        };
+        let overflowing = match subscriber.outbox.push(&payload) {
+            Some(buffered) => limit.exceeded(buffered, &mut subscriber.soft_limit_since),
+            None => true,
+        };
+        if overflowing {
+            subscriber.outbox.disconnect();
+        }
+        true
+    }
+
+    /// Channels or patterns with at least one subscriber, optionally only
+    /// those matching `pattern`.
+    pub fn active(&self, kind: Subscription, pattern: Option<&str>) -> Vec<&String> {
+        self.names(kind)
+            .keys()
+            .filter(|name| pattern.is_none_or(|p| glob_match(p.as_bytes(), name.as_bytes(), false)))
+            .collect()
+    }
+
+    pub fn subscriber_count(&self, kind: Subscription, name: &str) -> usize {
+        self.names(kind).get(name).map_or(0, |ids| ids.len())
+    }
+}
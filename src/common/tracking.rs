@@ -0,0 +1,109 @@
+//! Server-assisted client-side caching. Clients with CLIENT TRACKING on are
+//! told when keys they may have cached change: either the keys they read
+//! (the default mode) or every key under the prefixes they registered (BCAST).
+
+use std::collections::{HashMap, HashSet};
+
+/// Options given to CLIENT TRACKING ON.
+#[derive(Clone, Default)]
+pub struct TrackingOptions {
+    /// Client receiving the invalidation messages instead, or 0.
+    pub redirect: u64,
+    pub bcast: bool,
+    /// Only keys read right after CLIENT CACHING YES are tracked.
+    pub optin: bool,
+    /// Keys read right after CLIENT CACHING NO are not tracked.
+    pub optout: bool,
+    /// Changes a client makes itself are not reported to it.
+    pub noloop: bool,
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Tracking {
+    /// Clients that may have cached each key. Keys are tracked across all
+    /// databases, as in Redis.
+    table: HashMap<String, HashSet<u64>>,
+    /// Clients broadcasting each prefix.
+    prefixes: HashMap<String, HashSet<u64>>,
+    clients: HashMap<u64, TrackingOptions>,
+    /// Clients whose redirection target disconnected.
+    broken_redirects: HashSet<u64>,
+}
+
+impl Tracking {
+    pub fn new() -> Self {
+        Tracking::default()
+    }
+
+    pub fn enable(&mut self, id: u64, options: TrackingOptions) {
+        self.disable(id);
+        if options.bcast {
+            for prefix in &options.prefixes {
+                self.prefixes.entry(prefix.clone()).or_default().insert(id);
+            }
+        }
+        self.clients.insert(id, options);
+    }
+
+    /// Stops tracking for client `id`. Keys it read are forgotten lazily, the
+    /// next time they change.
+    pub fn disable(&mut self, id: u64) {
+        self.broken_redirects.remove(&id);
+        let Some(options) = self.clients.remove(&id) else {
+            return;
+        };
+        for prefix in &options.prefixes {
+            if let Some(ids) = self.prefixes.get_mut(prefix) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.prefixes.remove(prefix);
+                }
+            }
+        }
+    }
+
+    pub fn options(&self, id: u64) -> Option<&TrackingOptions> {
+        self.clients.get(&id)
+    }
+
+    pub fn mark_broken_redirect(&mut self, id: u64) {
+        self.broken_redirects.insert(id);
+    }
+
+    pub fn has_broken_redirect(&self, id: u64) -> bool {
+        self.broken_redirects.contains(&id)
+    }
+
+    /// Records that client `id` read `key`.
+    pub fn remember(&mut self, id: u64, key: &str) {
+        self.table.entry(key.to_string()).or_default().insert(id);
+    }
+
+    /// Clients to notify that `key` changed. Clients that read it must read
+    /// it again to keep being notified.
+    pub fn invalidate(&mut self, key: &str) -> Vec<u64> {
+        let mut ids: Vec<u64> = self
+            .table
+            .remove(key)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| self.clients.get(id).is_some_and(|options| !options.bcast))
+            .collect();
+
+        for (prefix, subscribers) in &self.prefixes {
+            if key.starts_with(prefix.as_str()) {
+                ids.extend(subscribers);
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Every tracking client, all of whose cached keys a flush invalidates.
+    pub fn flush(&mut self) -> Vec<u64> {
+        self.table.clear();
+        self.clients.keys().copied().collect()
+    }
+}
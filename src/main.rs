@@ -1,23 +1,41 @@
-use std::{
-    io::{BufReader, Read},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
-    thread,
+use std::sync::{Arc, Mutex};
+
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::AsyncReadExt,
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
 };
 
 use crate::{
-    common::Environment,
-    resp2::{serialization::Deserialize, Resp2},
+    common::{set_verbosity, Client, Config, Environment, Outbox, OutputBufferLimit},
+    resp2::{
+        reply,
+        serialization::{set_max_bulk_len, try_parse_one_command, Deserialize},
+        Resp2,
+    },
 };
 
+mod aof;
 mod common;
+mod rdb;
+mod replication;
 mod resp2;
+mod scripting;
+
+/// Bytes read from a connection at a time, at least.
+const READ_CHUNK: usize = 16 * 1024;
 
-fn main() {
+/// Bytes of replies a connection may leave unread before no more of its
+/// commands are read, so that its output cannot grow without bound.
+const OUTPUT_HIGH_WATER: usize = 1024 * 1024;
+
+#[tokio::main]
+async fn main() {
     let args: Vec<String> = std::env::args().collect();
     let mut port: u16 = 6379;
     let mut role = "master".to_string();
     let mut host = (String::new(), 0u16);
+    let mut config = Config::default();
 
     let mut i = 1;
     while i < args.len() {
@@ -28,6 +46,8 @@ fn main() {
                 println!("  -h, --help        Show this help message");
                 println!("  -v, --version     Show version information");
                 println!("  -p, --port <PORT> Specify the port to listen on (default: 6379)");
+                println!("  --replicaof \"<HOST> <PORT>\"  Replicate the given master");
+                println!("  --<option> <VALUE>  Set a configuration option (e.g. --repl-backlog-size 1mb)");
                 return;
             }
             "-v" | "--version" => {
@@ -70,6 +90,13 @@ fn main() {
                 role = "slave".to_string();
                 i += if args[i + 1].contains(' ') { 1 } else { 2 };
             }
+            option if option.starts_with("--") && i + 1 < args.len() => {
+                if let Err(e) = config.set(&option[2..], &args[i + 1]) {
+                    eprintln!("{}. Use -h for help.", e);
+                    return;
+                }
+                i += 1;
+            }
             unknown => {
                 eprintln!("Unknown argument '{}'. Use -h for help.", unknown);
                 return;
@@ -78,29 +105,33 @@ fn main() {
         i += 1;
     }
 
-    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
-    println!("Listening on 127.0.0.1:{}", port);
+    set_verbosity(config.loglevel);
+    set_max_bulk_len(config.proto_max_bulk_len);
+    let output_limits = (
+        config.normal_output_buffer_limit,
+        config.pubsub_output_buffer_limit,
+    );
+    let env = Arc::new(Mutex::new(Environment::new(role.clone(), port, config)));
+
+    if let Err(e) = aof::start(&env) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let env = Arc::new(Mutex::new(Environment::new(role.clone(), port)));
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    println!("Listening on 127.0.0.1:{}", port);
 
     if role == "slave" {
-        let mut master_stream = TcpStream::connect(host).expect("Failed to connect to master");
-
-        let mut init = Resp2::new(env.clone());
-        init.set_kind(resp2::command::RespCommand::INTITIALIZE);
-        if let Err(e) = init.reflect(&mut master_stream) {
-            eprintln!("Failed to initialize slave: {}", e);
-            return;
+        if let Ok(mut env) = env.lock() {
+            env.set_master(host);
         }
-
-        spawn_master_listener(master_stream, Arc::clone(&env));
+        replication::start(Arc::clone(&env));
     }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let env_clone = Arc::clone(&env);
-                thread::spawn(move || handle_client(stream, env_clone));
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(accept(stream, Arc::clone(&env), output_limits));
             }
             Err(e) => {
                 println!("Connection failed: {}", e);
@@ -109,102 +140,145 @@ fn main() {
     }
 }
 
-fn handle_client(mut stream: TcpStream, env: Arc<Mutex<Environment>>) {
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut buffer = Vec::new();
+async fn accept(
+    stream: TcpStream,
+    env: Arc<Mutex<Environment>>,
+    (normal, pubsub): (OutputBufferLimit, OutputBufferLimit),
+) {
+    match Outbox::spawn(stream) {
+        Ok((reader, outbox)) => {
+            let mut client = Client::new();
+            client.set_outbox(outbox);
+            client.set_output_limits(normal, pubsub);
+            handle_client(reader, BytesMut::new(), env, client).await;
+        }
+        Err(e) => println!("Failed to set up connection: {}", e),
+    }
+}
+
+/// Reads and runs the commands of a connection until it closes. `buffer`
+/// holds whatever was already read from it.
+async fn handle_client(
+    mut reader: OwnedReadHalf,
+    mut buffer: BytesMut,
+    env: Arc<Mutex<Environment>>,
+    mut client: Client,
+) {
+    let Some(outbox) = client.outbox().cloned() else {
+        return;
+    };
 
     loop {
-        let mut temp_buf = [0u8; 1024];
-        let n = match reader.get_mut().read(&mut temp_buf) {
-            Ok(0) => break,
-            Ok(n) => n,
-            Err(e) => {
-                println!("Failed to read from stream: {}", e);
+        if !buffer.is_empty() {
+            // Commands may wait on the environment's lock, on replicas in WAIT
+            // or on a script, so they run where blocking does not stall the
+            // other connections. Their replies are written together.
+            outbox.cork();
+            let ran = tokio::task::block_in_place(|| {
+                run_commands(&mut buffer, &env, &mut client, &outbox)
+            });
+            if client.output_limit_exceeded() {
+                println!(
+                    "Disconnecting client {}: output buffer is {} bytes",
+                    outbox
+                        .peer_addr()
+                        .map_or_else(String::new, |addr| addr.to_string()),
+                    outbox.buffered()
+                );
+                outbox.disconnect();
                 break;
             }
-        };
-
-        buffer.extend_from_slice(&temp_buf[..n]);
-
-        while let Some((command_bytes, used)) = match try_parse_one_command(&buffer) {
-            Ok(result) => result,
-            Err(e) => {
-                println!("Parse error: {}", e);
-                return;
+            let flushed = outbox.uncork();
+            let backlogged = match ran {
+                Ok(backlogged) => backlogged,
+                Err(e) => {
+                    println!("{}", e);
+                    break;
+                }
+            };
+            if !flushed {
+                break;
             }
-        } {
-            let mut resp2 = Resp2::new(env.clone());
-            resp2.set_literal(command_bytes.clone());
 
-            if let Err(e) = resp2.deserialize(command_bytes.clone()) {
-                println!("Failed to deserialize: {}", e);
-                return;
+            // Until a client reads its replies, it is not read from either
+            outbox.drained(OUTPUT_HIGH_WATER).await;
+            if backlogged {
+                continue;
             }
+        }
 
-            if let Err(e) = resp2.reflect(&mut stream) {
-                println!("Command error: {}", e);
-                return;
+        buffer.reserve(READ_CHUNK);
+        match reader.read_buf(&mut buffer).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                println!("Failed to read from stream: {}", e);
+                break;
             }
-
-            buffer.drain(..used);
         }
     }
-}
 
-fn spawn_master_listener(stream: TcpStream, env: Arc<Mutex<Environment>>) {
-    thread::spawn(move || handle_client(stream, env));
+    // A replica that hung up no longer receives the replication stream, and
+    // the stream from our master resumes in the database it left off in
+    client.close();
+    tokio::task::block_in_place(|| {
+        if let Ok(mut env) = env.lock() {
+            env.unwatch(client.id(), client.take_watched());
+            env.disconnect_client(&client);
+            env.remove_slave(client.id());
+            if client.is_master() {
+                env.set_master_db(client.db());
+            }
+        }
+    });
 }
 
-fn try_parse_one_command(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, String> {
-    if buf.is_empty() {
-        return Ok(None);
-    }
-
-    let input = match std::str::from_utf8(buf) {
-        Ok(s) => s,
-        Err(_) => return Ok(None),
-    };
-
-    let mut lines = input.split("\r\n").peekable();
-    let mut total = 0;
-
-    let first = match lines.next() {
-        Some(line) => line,
-        None => return Ok(None),
-    };
-    total += first.len() + 2;
-
-    if !first.starts_with('*') {
-        return Err(format!("Expected RESP array, got '{}'", first));
-    }
-
-    let count = match first[1..].parse::<usize>() {
-        Ok(n) => n,
-        Err(_) => return Err(format!("Invalid array count '{}'", first)),
-    };
-
-    for _ in 0..count {
-        let size_line = lines.next().ok_or("Missing $length line")?;
-        total += size_line.len() + 2;
-        if !size_line.starts_with('$') {
-            return Err(format!("Expected bulk string, got '{}'", size_line));
+/// Runs the complete commands in `buffer`, leaving a partial one for the
+/// next read. Returns true if it stopped early because `outbox` holds more
+/// than [`OUTPUT_HIGH_WATER`] bytes.
+fn run_commands(
+    buffer: &mut BytesMut,
+    env: &Arc<Mutex<Environment>>,
+    client: &mut Client,
+    outbox: &Outbox,
+) -> Result<bool, String> {
+    loop {
+        let (command_bytes, used) = match try_parse_one_command(buffer) {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(e) => {
+                // Like Redis, tell the client what was wrong before hanging up
+                if !client.is_master() {
+                    outbox.push(&reply::error(&format!("ERR Protocol error: {}", e)));
+                }
+                return Err(format!("Parse error: {}", e));
+            }
+        };
+        let mut resp2 = Resp2::new(env.clone());
+
+        resp2
+            .deserialize(command_bytes.clone())
+            .map_err(|e| format!("Failed to deserialize: {}", e))?;
+
+        resp2
+            .reflect(client)
+            .map_err(|e| format!("Command error: {}", e))?;
+
+        // Replicas count every byte of the replication stream they processed
+        // and relay it verbatim to their own replicas, which learn the database
+        // the stream is in from the snapshot they sync with
+        if client.is_master() {
+            let mut env = env
+                .lock()
+                .map_err(|e| format!("Failed to lock environment: {}", e))?;
+            env.propagate(&command_bytes);
+            env.set_master_db(client.db());
         }
 
-        let len = size_line[1..]
-            .parse::<usize>()
-            .map_err(|_| format!("Invalid bulk length '{}'", size_line))?;
-
-        let data_line = lines.next().ok_or("Missing bulk string value")?;
-        total += data_line.len() + 2;
-
-        if data_line.len() != len {
-            return Err("Bulk string length mismatch".to_string());
+        buffer.advance(used);
+        if outbox.buffered() > OUTPUT_HIGH_WATER {
+            return Ok(true);
         }
     }
-
-    if total > buf.len() {
-        return Ok(None);
-    }
-
-    Ok(Some((buf[..total].to_vec(), total)))
+    Ok(false)
 }
@@ -0,0 +1,414 @@
+//! Serialization of the dataset in the RDB format understood by Redis.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::common::Environment;
+
+const RDB_VERSION: &str = "0011";
+
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+/// Largest output an LZF stream can produce per input byte: a three byte
+/// back reference copies up to 264 bytes.
+const LZF_MAX_RATIO: usize = 88;
+
+/// Produces an RDB snapshot of the whole dataset.
+pub fn dump(env: &Environment) -> Vec<u8> {
+    let mut out = format!("REDIS{}", RDB_VERSION).into_bytes();
+
+    write_aux(&mut out, "redis-ver", "7.2.0");
+    write_aux(&mut out, "redis-bits", "64");
+    // Replicas relay their master's stream as is, so one syncing with us must
+    // know which database that stream has selected
+    write_aux(&mut out, "repl-stream-db", &env.master_db().to_string());
+    for code in function_codes(env) {
+        out.push(OPCODE_FUNCTION2);
+        write_string(&mut out, code.as_bytes());
+    }
+
+    let now = SystemTime::now();
+    for (index, db) in env.databases().iter().enumerate() {
+        let live: Vec<_> = db
+            .values()
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .collect();
+        if live.is_empty() {
+            continue;
+        }
+
+        out.push(OPCODE_SELECTDB);
+        write_length(&mut out, index as u64);
+        out.push(OPCODE_RESIZEDB);
+        write_length(&mut out, live.len() as u64);
+        let expires = live.iter().filter(|(_, entry)| entry.expiry.is_some()).count();
+        write_length(&mut out, expires as u64);
+
+        for (key, entry) in live {
+            if let Some(expiry) = entry.expiry {
+                let ms = expiry
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64);
+                out.push(OPCODE_EXPIRETIME_MS);
+                out.extend_from_slice(&ms.to_le_bytes());
+            }
+            out.push(TYPE_STRING);
+            write_string(&mut out, key.as_bytes());
+            write_string(&mut out, entry.value.as_bytes());
+        }
+    }
+
+    out.push(OPCODE_EOF);
+    let checksum = crc64(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Replaces the dataset with the contents of an RDB snapshot.
+pub fn load(env: &mut Environment, data: &[u8]) -> Result<(), String> {
+    let mut reader = Reader { data, pos: 0 };
+
+    let magic = reader.take(9)?;
+    if !magic.starts_with(b"REDIS") {
+        return Err("Invalid RDB header".to_string());
+    }
+
+    env.flush_all();
+    env.set_master_db(0);
+    if let Some(scripting) = env.scripting_mut() {
+        scripting.functions_mut().flush();
+    }
+    let now = SystemTime::now();
+    let mut expiry: Option<SystemTime> = None;
+    let mut db = 0;
+
+    loop {
+        let opcode = reader.byte()?;
+        match opcode {
+            OPCODE_EOF => break,
+            OPCODE_AUX => {
+                let key = reader.string()?;
+                let value = reader.string()?;
+                if key == b"repl-stream-db" {
+                    let db = std::str::from_utf8(&value)
+                        .ok()
+                        .and_then(|db| db.parse::<usize>().ok())
+                        .filter(|db| *db < env.databases().len())
+                        .ok_or("Invalid repl-stream-db in RDB")?;
+                    env.set_master_db(db);
+                }
+            }
+            OPCODE_FUNCTION2 => {
+                let code = reader.utf8()?;
+                if let Some(scripting) = env.scripting_mut() {
+                    scripting
+                        .functions_mut()
+                        .load(&code, true)
+                        .map_err(|e| format!("Failed loading library from RDB: {}", e))?;
+                }
+            }
+            OPCODE_SELECTDB => {
+                db = reader.length()? as usize;
+                if db >= env.databases().len() {
+                    return Err(format!("RDB selects database {} which is out of range", db));
+                }
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                let ms = u64::from_le_bytes(reader.array()?);
+                expiry = Some(UNIX_EPOCH + Duration::from_millis(ms));
+            }
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.array()?);
+                expiry = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+            }
+            TYPE_STRING => {
+                let key = reader.utf8()?;
+                let value = reader.utf8()?;
+
+                // Masters drop keys that expired while the snapshot was stored;
+                // replicas wait for the DEL from their master instead
+                let expired = expiry.is_some_and(|expiry| expiry <= now);
+                if !expired || env.role() != "master" {
+                    env.insert_loaded(db, key, value, expiry);
+                }
+                expiry = None;
+            }
+            other => return Err(format!("Unsupported RDB value type {}", other)),
+        }
+    }
+
+    if data.len() >= reader.pos + 8 {
+        let expected = u64::from_le_bytes(reader.array()?);
+        if expected != 0 && expected != crc64(&data[..reader.pos - 8]) {
+            return Err("RDB checksum mismatch".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn function_codes(env: &Environment) -> Vec<String> {
+    env.scripting()
+        .map(|scripting| scripting.functions().codes())
+        .unwrap_or_default()
+}
+
+/// Serializes function libraries the way FUNCTION DUMP does: each library as
+/// in a snapshot, followed by the RDB version and a checksum.
+pub fn dump_functions(codes: &[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for code in codes {
+        out.push(OPCODE_FUNCTION2);
+        write_string(&mut out, code.as_bytes());
+    }
+    let version: u16 = RDB_VERSION.parse().unwrap_or_default();
+    out.extend_from_slice(&version.to_le_bytes());
+    let checksum = crc64(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Reads back the libraries of a FUNCTION DUMP payload.
+pub fn load_functions(payload: &[u8]) -> Result<Vec<String>, String> {
+    if payload.len() < 10 {
+        return Err("Payload too short".to_string());
+    }
+    let (body, checksum) = payload.split_at(payload.len() - 8);
+    let (data, version) = body.split_at(body.len() - 2);
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version > RDB_VERSION.parse().unwrap_or_default()
+        || u64::from_le_bytes(checksum.try_into().unwrap_or_default()) != crc64(body)
+    {
+        return Err("Payload version or checksum are wrong".to_string());
+    }
+
+    let mut reader = Reader { data, pos: 0 };
+    let mut codes = Vec::new();
+    while reader.pos < data.len() {
+        if reader.byte()? != OPCODE_FUNCTION2 {
+            return Err("Given type is not a function".to_string());
+        }
+        codes.push(reader.utf8()?);
+    }
+    Ok(codes)
+}
+
+fn write_aux(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.push(OPCODE_AUX);
+    write_string(out, key.as_bytes());
+    write_string(out, value.as_bytes());
+}
+
+fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, value: &[u8]) {
+    write_length(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// CRC-64/Jones, the checksum Redis appends to RDB files.
+fn crc64(data: &[u8]) -> u64 {
+    let mut crc = 0u64;
+    for &byte in data {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x95AC_9329_AC4B_C9B5
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: u64) -> Result<&[u8], String> {
+        let end = usize::try_from(n)
+            .ok()
+            .and_then(|n| self.pos.checked_add(n))
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| "Unexpected end of RDB data".to_string())?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N as u64)?);
+        Ok(out)
+    }
+
+    fn raw_length(&mut self) -> Result<Length, String> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Plain((first & 0x3F) as u64),
+            1 => Length::Plain((((first & 0x3F) as u64) << 8) | self.byte()? as u64),
+            2 => match first {
+                0x80 => Length::Plain(u32::from_be_bytes(self.array()?) as u64),
+                0x81 => Length::Plain(u64::from_be_bytes(self.array()?)),
+                _ => return Err(format!("Invalid RDB length prefix {:#x}", first)),
+            },
+            _ => Length::Encoded(first & 0x3F),
+        })
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        match self.raw_length()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => Err("Unexpected encoded length in RDB".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        match self.raw_length()? {
+            Length::Plain(len) => Ok(self.take(len)?.to_vec()),
+            Length::Encoded(ENCODING_INT8) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENCODING_INT16) => {
+                Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENCODING_INT32) => {
+                Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed = self.length()?;
+                let original = self.length()?;
+                lzf_decompress(self.take(compressed)?, original)
+            }
+            Length::Encoded(other) => Err(format!("Unknown RDB string encoding {}", other)),
+        }
+    }
+
+    fn utf8(&mut self) -> Result<String, String> {
+        String::from_utf8(self.string()?).map_err(|e| e.to_string())
+    }
+}
+
+fn lzf_decompress(input: &[u8], original: u64) -> Result<Vec<u8>, String> {
+    let corrupt = || "Corrupt LZF string in RDB".to_string();
+    let original = usize::try_from(original)
+        .ok()
+        .filter(|original| *original <= input.len().saturating_mul(LZF_MAX_RATIO))
+        .ok_or_else(corrupt)?;
+    let mut out = Vec::with_capacity(original);
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            if out.len() + literal.len() > original {
+                return Err(corrupt());
+            }
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let back = ((ctrl & 0x1F) << 8) + *input.get(i).ok_or_else(corrupt)? as usize + 1;
+            i += 1;
+
+            let start = out.len().checked_sub(back).ok_or_else(corrupt)?;
+            if out.len() + len + 2 > original {
+                return Err(corrupt());
+            }
+            for j in 0..len + 2 {
+                out.push(out[start + j]);
+            }
+        }
+    }
+
+    if out.len() != original {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lzf_decompress, Reader};
+
+    #[test]
+    fn decompresses_literals_and_back_references() {
+        // "abc" as a literal run, then a back reference copying it twice.
+        let input = [0x02, b'a', b'b', b'c', 0x80, 0x02];
+        assert_eq!(lzf_decompress(&input, 9).unwrap(), b"abcabcabc");
+    }
+
+    #[test]
+    fn rejects_lzf_lengths_the_input_cannot_produce() {
+        let input = [0x02, b'a', b'b', b'c'];
+        assert!(lzf_decompress(&input, u64::MAX).is_err());
+        assert!(lzf_decompress(&input, 1 << 40).is_err());
+        assert!(lzf_decompress(&input, 2).is_err());
+    }
+
+    #[test]
+    fn rejects_string_lengths_past_the_end() {
+        let data = [0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, b'x'];
+        let mut reader = Reader { data: &data, pos: 0 };
+        assert!(reader.string().is_err());
+
+        // LZF header claiming an enormous compressed and original size.
+        let data = [0xC3, 0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xF0, 0x01, 0x00];
+        let mut reader = Reader { data: &data, pos: 0 };
+        assert!(reader.string().is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use rand::{distr::Alphanumeric, Rng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpStream},
+    time::timeout,
+};
+
+use crate::{
+    aof,
+    common::{Client, Environment, Outbox, ReplState, SlaveConnection},
+    rdb,
+    resp2::serialization::encode_command,
+};
+
+const RETRY_INITIAL: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Spawns the task that keeps this replica linked with the master currently
+/// configured in the environment, reconnecting with backoff whenever the link
+/// cannot be established or drops. Must be called from within the runtime.
+pub fn start(env: Arc<Mutex<Environment>>) {
+    let epoch = match env.lock() {
+        Ok(env) => env.master_link_epoch(),
+        Err(_) => return,
+    };
+
+    tokio::spawn(async move {
+        let mut backoff = RETRY_INITIAL;
+
+        loop {
+            let host = match current_master(&env, epoch) {
+                Some(host) => host,
+                None => return,
+            };
+
+            match connect(&env, epoch, &host).await {
+                Ok(link) => {
+                    backoff = RETRY_INITIAL;
+                    follow_master(link, Arc::clone(&env)).await;
+                    println!("Lost connection with master {}:{}", host.0, host.1);
+                }
+                Err(e) => {
+                    println!("Failed to sync with master {}:{}: {}", host.0, host.1, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RETRY_MAX);
+                }
+            }
+
+            match env.lock() {
+                Ok(mut env) if env.master_link_epoch() == epoch => {
+                    env.set_repl_state(ReplState::Connect)
+                }
+                _ => return,
+            }
+        }
+    });
+}
+
+fn current_master(env: &Arc<Mutex<Environment>>, epoch: u64) -> Option<(String, u16)> {
+    let env = env.lock().ok()?;
+    if env.master_link_epoch() != epoch {
+        return None;
+    }
+    env.master_host().cloned()
+}
+
+fn set_state(env: &Arc<Mutex<Environment>>, epoch: u64, state: ReplState) -> Result<(), String> {
+    let mut env = env.lock().map_err(|e| e.to_string())?;
+    if env.master_link_epoch() != epoch {
+        return Err("Master changed during synchronization".to_string());
+    }
+    env.set_repl_state(state);
+    Ok(())
+}
+
+/// Connection with the master during the handshake. Whatever was read past
+/// the snapshot is the start of the replication stream, and stays buffered.
+struct Handshake {
+    stream: TcpStream,
+    buffer: BytesMut,
+}
+
+impl Handshake {
+    async fn send(&mut self, args: &[&str]) -> Result<(), String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        self.stream
+            .write_all(&encode_command(&args))
+            .await
+            .map_err(|e| format!("Failed to send {} to master: {}", args[0], e))
+    }
+
+    async fn fill(&mut self) -> Result<(), String> {
+        let read = timeout(HANDSHAKE_TIMEOUT, self.stream.read_buf(&mut self.buffer))
+            .await
+            .map_err(|_| "Timed out waiting for master".to_string())?
+            .map_err(|e| format!("Failed to read from master: {}", e))?;
+        if read == 0 {
+            return Err("Connection closed by master".to_string());
+        }
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buffer.split_to(end);
+                self.buffer.advance(2);
+                return String::from_utf8(line.to_vec())
+                    .map_err(|e| format!("Failed to convert bytes to string: {}", e));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn expect(&mut self, reply: &str) -> Result<(), String> {
+        let line = self.read_line().await?;
+        if !line.starts_with(reply) {
+            return Err(format!("Unexpected response from master: '{}'", line));
+        }
+        Ok(())
+    }
+
+    async fn read_exact(&mut self, size: usize) -> Result<Vec<u8>, String> {
+        while self.buffer.len() < size {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(size).to_vec())
+    }
+
+    /// Reads an RDB payload terminated by `mark`.
+    async fn read_until_mark(&mut self, mark: &[u8]) -> Result<Vec<u8>, String> {
+        let mut scanned = 0;
+        loop {
+            let found = self.buffer[scanned..]
+                .windows(mark.len())
+                .position(|w| w == mark);
+            if let Some(pos) = found {
+                let rdb = self.buffer.split_to(scanned + pos);
+                self.buffer.advance(mark.len());
+                return Ok(rdb.to_vec());
+            }
+            // The mark may straddle what is buffered and what comes next
+            scanned = self.buffer.len().saturating_sub(mark.len() - 1);
+            self.fill().await?;
+        }
+    }
+}
+
+/// Link with the master, ready to receive the replication stream.
+struct MasterLink {
+    reader: OwnedReadHalf,
+    outbox: Outbox,
+    buffer: BytesMut,
+}
+
+/// Connects to the master and runs the handshake, leaving the link ready to
+/// receive the replication stream.
+async fn connect(
+    env: &Arc<Mutex<Environment>>,
+    epoch: u64,
+    host: &(String, u16),
+) -> Result<MasterLink, String> {
+    set_state(env, epoch, ReplState::Connecting)?;
+
+    let stream = timeout(
+        CONNECT_TIMEOUT,
+        TcpStream::connect((host.0.as_str(), host.1)),
+    )
+    .await
+    .map_err(|_| "Timed out connecting to master".to_string())?
+    .map_err(|e| format!("Failed to connect to master: {}", e))?;
+
+    set_state(env, epoch, ReplState::Handshake)?;
+
+    let mut handshake = Handshake {
+        stream,
+        buffer: BytesMut::new(),
+    };
+    sync(env, &mut handshake).await?;
+    let (reader, outbox) = Outbox::spawn(handshake.stream)?;
+
+    let mut env = env.lock().map_err(|e| e.to_string())?;
+    if env.master_link_epoch() != epoch {
+        outbox.disconnect();
+        return Err("Master changed during synchronization".to_string());
+    }
+    env.set_repl_state(ReplState::Connected);
+    env.set_master_link(outbox.clone());
+
+    Ok(MasterLink {
+        reader,
+        outbox,
+        buffer: handshake.buffer,
+    })
+}
+
+/// Introduces ourselves to the master, then either loads the snapshot it
+/// sends or resumes our previous history.
+async fn sync(env: &Arc<Mutex<Environment>>, handshake: &mut Handshake) -> Result<(), String> {
+    let port = env.lock().map_err(|e| e.to_string())?.port();
+
+    handshake.send(&["PING"]).await?;
+    handshake.expect("+PONG").await?;
+
+    handshake
+        .send(&["REPLCONF", "listening-port", &port.to_string()])
+        .await?;
+    handshake.expect("+OK").await?;
+
+    handshake
+        .send(&["REPLCONF", "capa", "eof", "capa", "psync2"])
+        .await?;
+    handshake.expect("+OK").await?;
+
+    // PSYNC <REPLID> <OFFSET>, resuming our previous history when we have one
+    let (replid, offset) = {
+        let env = env.lock().map_err(|e| e.to_string())?;
+        if env.cached_master() {
+            (
+                env.master_replid().to_string(),
+                (env.master_repl_offset() + 1).to_string(),
+            )
+        } else {
+            ("?".to_string(), "-1".to_string())
+        }
+    };
+    handshake.send(&["PSYNC", &replid, &offset]).await?;
+
+    let header = handshake.read_line().await?;
+    let parts: Vec<&str> = header.split_whitespace().collect();
+
+    match parts.first().copied() {
+        Some("+FULLRESYNC") => {
+            if parts.len() < 3 {
+                return Err("Invalid FULLRESYNC response from master".to_string());
+            }
+            let replid = parts[1].to_string();
+            let offset = parts[2]
+                .parse::<u64>()
+                .map_err(|_| "Invalid offset in FULLRESYNC response".to_string())?;
+
+            env.lock()
+                .map_err(|e| e.to_string())?
+                .set_repl_state(ReplState::Transfer);
+
+            // $<LENGTH>\r\n<RDB> or, for diskless transfers, $EOF:<MARK>\r\n<RDB><MARK>
+            let size_line = handshake.read_line().await?;
+            let size_line = size_line.trim_start_matches('\n');
+            let rdb = match size_line.strip_prefix("$EOF:") {
+                Some(mark) if mark.len() == 40 => {
+                    handshake.read_until_mark(mark.as_bytes()).await?
+                }
+                _ => {
+                    let size = size_line
+                        .strip_prefix('$')
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .ok_or_else(|| {
+                            format!("Invalid RDB header from master: '{}'", size_line)
+                        })?;
+                    handshake.read_exact(size).await?
+                }
+            };
+
+            // Loading may take a while, during which the dataset is locked anyway
+            tokio::task::block_in_place(|| {
+                let mut locked = env.lock().map_err(|e| e.to_string())?;
+                rdb::load(&mut locked, &rdb)
+                    .map_err(|e| format!("Failed to load RDB from master: {}", e))?;
+                locked.reset_replication(replid, offset);
+                aof::rewrite_after_sync(env, &mut locked)
+            })
+        }
+        Some("+CONTINUE") => {
+            if let Some(replid) = parts.get(1) {
+                let mut env = env.lock().map_err(|e| e.to_string())?;
+                env.switch_master_replid(replid.to_string());
+            }
+            Ok(())
+        }
+        _ => Err(format!("Unexpected response from master: '{}'", header)),
+    }
+}
+
+/// Applies the master's replication stream until the link drops.
+async fn follow_master(link: MasterLink, env: Arc<Mutex<Environment>>) {
+    let db = match env.lock() {
+        Ok(env) => env.master_db(),
+        Err(_) => return,
+    };
+    spawn_ack_sender(link.outbox.clone(), Arc::clone(&env));
+
+    let mut client = Client::master(db);
+    client.set_outbox(link.outbox);
+    crate::handle_client(link.reader, link.buffer, env, client).await;
+}
+
+/// Acknowledges our replication offset to the master once per second, so it
+/// can report how far behind we are.
+fn spawn_ack_sender(link: Outbox, env: Arc<Mutex<Environment>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            let offset = tokio::task::block_in_place(|| {
+                env.lock().map(|env| env.master_repl_offset()).ok()
+            });
+            let Some(offset) = offset else {
+                return;
+            };
+
+            let ack = encode_command(&[
+                "REPLCONF".to_string(),
+                "ACK".to_string(),
+                offset.to_string(),
+            ]);
+            if link.push(&ack).is_none() {
+                return;
+            }
+        }
+    });
+}
+
+/// Starts a full synchronization for a replica. With `repl-diskless-sync`
+/// and replicas that understand EOF framing, the transfer is delayed so that
+/// replicas arriving meanwhile share it.
+pub fn full_sync(
+    env_arc: Arc<Mutex<Environment>>,
+    env: &mut Environment,
+    slave: SlaveConnection,
+    eof_capable: bool,
+) {
+    if !env.config().repl_diskless_sync || !eof_capable {
+        transfer(env, vec![slave], false);
+        return;
+    }
+
+    if env.queue_full_sync(slave) {
+        let delay = Duration::from_secs(env.config().repl_diskless_sync_delay);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            tokio::task::block_in_place(|| {
+                if let Ok(mut env) = env_arc.lock() {
+                    let slaves = env.take_full_syncs();
+                    transfer(&mut env, slaves, true);
+                }
+            });
+        });
+    }
+}
+
+/// Snapshots the dataset once and streams it to every replica in `slaves`,
+/// either length-prefixed or framed by an EOF mark for diskless transfers.
+fn transfer(env: &mut Environment, slaves: Vec<SlaveConnection>, diskless: bool) {
+    if slaves.is_empty() {
+        return;
+    }
+
+    let offset = env.master_repl_offset();
+    let rdb = rdb::dump(env);
+    env.reset_repl_selected_db();
+
+    let mut payload = format!("+FULLRESYNC {} {}\r\n", env.master_replid(), offset).into_bytes();
+    if diskless {
+        let mark: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+        payload.extend_from_slice(format!("$EOF:{}\r\n", mark).as_bytes());
+        payload.extend_from_slice(&rdb);
+        payload.extend_from_slice(mark.as_bytes());
+    } else {
+        payload.extend_from_slice(format!("${}\r\n", rdb.len()).as_bytes());
+        payload.extend_from_slice(&rdb);
+    }
+
+    for mut slave in slaves {
+        slave.set_offset(offset);
+        if slave.send_snapshot(&payload) {
+            env.add_slave(slave);
+        }
+    }
+}
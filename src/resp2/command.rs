@@ -1,5 +1,29 @@
 use std::fmt::Display;
 
+/// The command modifies the dataset and is replicated.
+pub const WRITE: u32 = 1 << 0;
+/// The command only reads the dataset.
+pub const READONLY: u32 = 1 << 1;
+/// The command administers the server or its replication.
+pub const ADMIN: u32 = 1 << 2;
+
+/// The command may grow the dataset, so it is refused once `maxmemory` is
+/// reached and nothing can be evicted.
+pub const DENYOOM: u32 = 1 << 3;
+/// The command cannot be queued inside a MULTI transaction.
+pub const NO_MULTI: u32 = 1 << 4;
+/// The command cannot be called from a Lua script.
+pub const NO_SCRIPT: u32 = 1 << 5;
+
+/// Static information about a command, following Redis' command table.
+pub struct CommandMeta {
+    /// Exact argument count including the command name, or the minimum as a
+    /// negative number for variadic commands.
+    pub arity: i32,
+    pub flags: u32,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub enum RespCommand {
     PING,
     UNDEFINED,
@@ -7,10 +31,49 @@ pub enum RespCommand {
     ECHO,
     SET,
     GET,
+    DEL,
     INFO,
-    INTITIALIZE,
     REPLCONF,
     PSYNC,
+    WAIT,
+    REPLICAOF,
+    BGREWRITEAOF,
+    SELECT,
+    MOVE,
+    SWAPDB,
+    FLUSHDB,
+    FLUSHALL,
+    DBSIZE,
+    KEYS,
+    SCAN,
+    HSCAN,
+    SSCAN,
+    ZSCAN,
+    RANDOMKEY,
+    OBJECT,
+    MULTI,
+    EXEC,
+    DISCARD,
+    WATCH,
+    UNWATCH,
+    EVAL,
+    EVALSHA,
+    SCRIPT,
+    FUNCTION,
+    FCALL,
+    FCALLRO,
+    SUBSCRIBE,
+    UNSUBSCRIBE,
+    PSUBSCRIBE,
+    PUNSUBSCRIBE,
+    PUBLISH,
+    PUBSUB,
+    SSUBSCRIBE,
+    SUNSUBSCRIBE,
+    SPUBLISH,
+    HELLO,
+    CLIENT,
+    CONFIG,
 }
 
 impl RespCommand {
@@ -21,13 +84,148 @@ impl RespCommand {
             "ECHO" => RespCommand::ECHO,
             "SET" => RespCommand::SET,
             "GET" => RespCommand::GET,
+            "DEL" => RespCommand::DEL,
             "INFO" => RespCommand::INFO,
-            "INTITIALIZE" => RespCommand::INTITIALIZE,
             "REPLCONF" => RespCommand::REPLCONF,
             "PSYNC" => RespCommand::PSYNC,
+            "WAIT" => RespCommand::WAIT,
+            "REPLICAOF" | "SLAVEOF" => RespCommand::REPLICAOF,
+            "BGREWRITEAOF" => RespCommand::BGREWRITEAOF,
+            "SELECT" => RespCommand::SELECT,
+            "MOVE" => RespCommand::MOVE,
+            "SWAPDB" => RespCommand::SWAPDB,
+            "FLUSHDB" => RespCommand::FLUSHDB,
+            "FLUSHALL" => RespCommand::FLUSHALL,
+            "DBSIZE" => RespCommand::DBSIZE,
+            "KEYS" => RespCommand::KEYS,
+            "SCAN" => RespCommand::SCAN,
+            "HSCAN" => RespCommand::HSCAN,
+            "SSCAN" => RespCommand::SSCAN,
+            "ZSCAN" => RespCommand::ZSCAN,
+            "RANDOMKEY" => RespCommand::RANDOMKEY,
+            "OBJECT" => RespCommand::OBJECT,
+            "MULTI" => RespCommand::MULTI,
+            "EXEC" => RespCommand::EXEC,
+            "DISCARD" => RespCommand::DISCARD,
+            "WATCH" => RespCommand::WATCH,
+            "UNWATCH" => RespCommand::UNWATCH,
+            "EVAL" => RespCommand::EVAL,
+            "EVALSHA" => RespCommand::EVALSHA,
+            "SCRIPT" => RespCommand::SCRIPT,
+            "FUNCTION" => RespCommand::FUNCTION,
+            "FCALL" => RespCommand::FCALL,
+            "FCALL_RO" => RespCommand::FCALLRO,
+            "SUBSCRIBE" => RespCommand::SUBSCRIBE,
+            "UNSUBSCRIBE" => RespCommand::UNSUBSCRIBE,
+            "PSUBSCRIBE" => RespCommand::PSUBSCRIBE,
+            "PUNSUBSCRIBE" => RespCommand::PUNSUBSCRIBE,
+            "PUBLISH" => RespCommand::PUBLISH,
+            "PUBSUB" => RespCommand::PUBSUB,
+            "SSUBSCRIBE" => RespCommand::SSUBSCRIBE,
+            "SUNSUBSCRIBE" => RespCommand::SUNSUBSCRIBE,
+            "SPUBLISH" => RespCommand::SPUBLISH,
+            "HELLO" => RespCommand::HELLO,
+            "CLIENT" => RespCommand::CLIENT,
+            "CONFIG" => RespCommand::CONFIG,
             _ => RespCommand::UNDEFINED,
         }
     }
+
+    pub fn meta(&self) -> CommandMeta {
+        let (arity, flags) = match self {
+            RespCommand::PING => (-1, 0),
+            RespCommand::UNDEFINED => (0, 0),
+            RespCommand::PONG => (-1, 0),
+            RespCommand::ECHO => (2, 0),
+            RespCommand::SET => (-3, WRITE | DENYOOM),
+            RespCommand::GET => (2, READONLY),
+            RespCommand::DEL => (-2, WRITE),
+            RespCommand::INFO => (-1, NO_MULTI | NO_SCRIPT),
+            RespCommand::REPLCONF => (-1, ADMIN | NO_MULTI | NO_SCRIPT),
+            RespCommand::PSYNC => (-3, ADMIN | NO_MULTI | NO_SCRIPT),
+            RespCommand::WAIT => (3, NO_MULTI | NO_SCRIPT),
+            RespCommand::REPLICAOF => (3, ADMIN | NO_MULTI | NO_SCRIPT),
+            RespCommand::BGREWRITEAOF => (1, ADMIN | NO_MULTI | NO_SCRIPT),
+            RespCommand::SELECT => (2, 0),
+            RespCommand::MOVE => (3, WRITE),
+            RespCommand::SWAPDB => (3, WRITE),
+            RespCommand::FLUSHDB => (-1, WRITE),
+            RespCommand::FLUSHALL => (-1, WRITE),
+            RespCommand::DBSIZE => (1, READONLY),
+            RespCommand::KEYS => (2, READONLY),
+            RespCommand::SCAN => (-2, READONLY),
+            RespCommand::HSCAN => (-3, READONLY),
+            RespCommand::SSCAN => (-3, READONLY),
+            RespCommand::ZSCAN => (-3, READONLY),
+            RespCommand::RANDOMKEY => (1, READONLY),
+            RespCommand::OBJECT => (-2, READONLY),
+            RespCommand::MULTI => (1, NO_MULTI | NO_SCRIPT),
+            RespCommand::EXEC => (1, NO_MULTI | NO_SCRIPT),
+            RespCommand::DISCARD => (1, NO_MULTI | NO_SCRIPT),
+            RespCommand::WATCH => (-2, NO_MULTI | NO_SCRIPT),
+            RespCommand::UNWATCH => (1, NO_SCRIPT),
+            RespCommand::EVAL => (-3, NO_SCRIPT),
+            RespCommand::EVALSHA => (-3, NO_SCRIPT),
+            RespCommand::SCRIPT => (-2, NO_SCRIPT),
+            RespCommand::FUNCTION => (-2, NO_SCRIPT),
+            RespCommand::FCALL => (-3, NO_SCRIPT),
+            RespCommand::FCALLRO => (-3, READONLY | NO_SCRIPT),
+            RespCommand::SUBSCRIBE => (-2, NO_MULTI | NO_SCRIPT),
+            RespCommand::UNSUBSCRIBE => (-1, NO_MULTI | NO_SCRIPT),
+            RespCommand::PSUBSCRIBE => (-2, NO_MULTI | NO_SCRIPT),
+            RespCommand::PUNSUBSCRIBE => (-1, NO_MULTI | NO_SCRIPT),
+            RespCommand::PUBLISH => (3, 0),
+            RespCommand::PUBSUB => (-2, 0),
+            RespCommand::SSUBSCRIBE => (-2, NO_MULTI | NO_SCRIPT),
+            RespCommand::SUNSUBSCRIBE => (-1, NO_MULTI | NO_SCRIPT),
+            RespCommand::SPUBLISH => (3, 0),
+            RespCommand::HELLO => (-1, NO_MULTI | NO_SCRIPT),
+            RespCommand::CLIENT => (-2, NO_SCRIPT),
+            RespCommand::CONFIG => (-2, ADMIN | NO_SCRIPT),
+        };
+        CommandMeta { arity, flags }
+    }
+
+    /// Whether the command modifies the dataset.
+    pub fn is_write(&self) -> bool {
+        self.meta().flags & WRITE != 0
+    }
+
+    pub fn denies_oom(&self) -> bool {
+        self.meta().flags & DENYOOM != 0
+    }
+
+    pub fn allowed_in_multi(&self) -> bool {
+        self.meta().flags & NO_MULTI == 0
+    }
+
+    pub fn allowed_in_script(&self) -> bool {
+        self.meta().flags & NO_SCRIPT == 0
+    }
+
+    /// Whether a connection in subscribed mode may run the command.
+    pub fn allowed_when_subscribed(&self) -> bool {
+        matches!(
+            self,
+            RespCommand::SUBSCRIBE
+                | RespCommand::UNSUBSCRIBE
+                | RespCommand::PSUBSCRIBE
+                | RespCommand::PUNSUBSCRIBE
+                | RespCommand::SSUBSCRIBE
+                | RespCommand::SUNSUBSCRIBE
+                | RespCommand::PING
+        )
+    }
+
+    /// Whether `argc` arguments (including the command name) satisfy the arity.
+    pub fn accepts(&self, argc: usize) -> bool {
+        let arity = self.meta().arity;
+        if arity >= 0 {
+            argc == arity as usize
+        } else {
+            argc >= arity.unsigned_abs() as usize
+        }
+    }
 }
 
 impl Display for RespCommand {
@@ -39,10 +237,49 @@ impl Display for RespCommand {
             RespCommand::ECHO => write!(f, "ECHO"),
             RespCommand::SET => write!(f, "SET"),
             RespCommand::GET => write!(f, "GET"),
+            RespCommand::DEL => write!(f, "DEL"),
             RespCommand::INFO => write!(f, "INFO"),
-            RespCommand::INTITIALIZE => write!(f, "INTITIALIZE"),
             RespCommand::REPLCONF => write!(f, "REPLCONF"),
             RespCommand::PSYNC => write!(f, "PSYNC"),
+            RespCommand::WAIT => write!(f, "WAIT"),
+            RespCommand::REPLICAOF => write!(f, "REPLICAOF"),
+            RespCommand::BGREWRITEAOF => write!(f, "BGREWRITEAOF"),
+            RespCommand::SELECT => write!(f, "SELECT"),
+            RespCommand::MOVE => write!(f, "MOVE"),
+            RespCommand::SWAPDB => write!(f, "SWAPDB"),
+            RespCommand::FLUSHDB => write!(f, "FLUSHDB"),
+            RespCommand::FLUSHALL => write!(f, "FLUSHALL"),
+            RespCommand::DBSIZE => write!(f, "DBSIZE"),
+            RespCommand::KEYS => write!(f, "KEYS"),
+            RespCommand::SCAN => write!(f, "SCAN"),
+            RespCommand::HSCAN => write!(f, "HSCAN"),
+            RespCommand::SSCAN => write!(f, "SSCAN"),
+            RespCommand::ZSCAN => write!(f, "ZSCAN"),
+            RespCommand::RANDOMKEY => write!(f, "RANDOMKEY"),
+            RespCommand::OBJECT => write!(f, "OBJECT"),
+            RespCommand::MULTI => write!(f, "MULTI"),
+            RespCommand::EXEC => write!(f, "EXEC"),
+            RespCommand::DISCARD => write!(f, "DISCARD"),
+            RespCommand::WATCH => write!(f, "WATCH"),
+            RespCommand::UNWATCH => write!(f, "UNWATCH"),
+            RespCommand::EVAL => write!(f, "EVAL"),
+            RespCommand::EVALSHA => write!(f, "EVALSHA"),
+            RespCommand::SCRIPT => write!(f, "SCRIPT"),
+            RespCommand::FUNCTION => write!(f, "FUNCTION"),
+            RespCommand::FCALL => write!(f, "FCALL"),
+            RespCommand::FCALLRO => write!(f, "FCALL_RO"),
+            RespCommand::SUBSCRIBE => write!(f, "SUBSCRIBE"),
+            RespCommand::UNSUBSCRIBE => write!(f, "UNSUBSCRIBE"),
+            RespCommand::PSUBSCRIBE => write!(f, "PSUBSCRIBE"),
+            RespCommand::PUNSUBSCRIBE => write!(f, "PUNSUBSCRIBE"),
+            RespCommand::PUBLISH => write!(f, "PUBLISH"),
+            RespCommand::PUBSUB => write!(f, "PUBSUB"),
+            RespCommand::SSUBSCRIBE => write!(f, "SSUBSCRIBE"),
+            RespCommand::SUNSUBSCRIBE => write!(f, "SUNSUBSCRIBE"),
+            RespCommand::SPUBLISH => write!(f, "SPUBLISH"),
+            RespCommand::HELLO => write!(f, "HELLO"),
+            RespCommand::CLIENT => write!(f, "CLIENT"),
+            RespCommand::CONFIG => write!(f, "CONFIG"),
         }
     }
 }
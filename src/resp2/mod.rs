@@ -1,24 +1,28 @@
 pub mod command;
+pub mod reply;
 pub mod serialization;
 
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use base64::engine::general_purpose::STANDARD;
-use base64::Engine;
-
 use command::*;
 use serialization::*;
 
-use crate::common::Environment;
+use crate::{
+    aof,
+    common::{
+        glob_match, perform_evictions, Client, Environment, ReplState, SlaveConnection,
+        Subscription, TrackingOptions, NOTIFY_GENERIC, NOTIFY_KEY_MISS, PARAMETERS,
+    },
+    replication, scripting,
+};
 
 pub struct Resp2 {
     kind: RespCommand,
     data: Vec<String>,
-    literal: Vec<u8>,
+    rewritten: Option<Vec<String>>,
     environment: Arc<Mutex<Environment>>,
 }
 
@@ -27,75 +31,121 @@ impl Resp2 {
         Resp2 {
             kind: RespCommand::UNDEFINED,
             data: Vec::new(),
-            literal: vec![],
+            rewritten: None,
             environment,
         }
     }
 
-    pub fn reflect(&mut self, mut stream: &mut TcpStream) -> Result<(), String> {
-        match self.kind {
-            RespCommand::PING => {
-                stream
-                    .write_all(b"+PONG\r\n")
-                    .map_err(|e| format!("Failed to write to stream: {}", e))?;
-            }
-            RespCommand::ECHO => {
-                let msg = self.data.get(1).cloned().unwrap_or_default();
-                let response = format!("+{}\r\n", msg);
-                stream
-                    .write_all(response.as_bytes())
-                    .map_err(|e| format!("Failed to write to stream: {}", e))?;
-            }
-            RespCommand::SET => {
-                if self.data.len() < 3 {
-                    return Err("SET command requires at least 3 arguments".to_string());
-                }
-                let key = &self.data[1];
-                let value = &self.data[2];
-
-                let mut exp: Option<u64> = None;
-                if self.data.len() == 5 && self.data[3].to_uppercase() == "PX" {
-                    let ms = &self.data[4];
-                    exp = match ms.parse::<u64>() {
-                        Ok(val) => Some(val),
-                        Err(_) => {
-                            return Err(format!("Invalid PX value: '{}'", ms));
-                        }
-                    };
-                }
+    pub fn name(&self) -> &str {
+        self.data.first().map_or("", |name| name.as_str())
+    }
 
-                let mut env = self.environment.lock().map_err(|e| e.to_string())?;
-                env.set(key.clone(), value.clone(), exp);
+    pub fn reflect(&mut self, client: &mut Client) -> Result<(), String> {
+        if !self.data.is_empty() && !self.kind.accepts(self.data.len()) {
+            // A command rejected while queuing makes the whole transaction fail
+            client.fail_multi();
+            let name = self.data[0].clone();
+            return match self.kind {
+                RespCommand::UNDEFINED => self.respond(client, b"-ERR unknown command\r\n"),
+                _ => self.respond(client, &reply::wrong_arity(&name)),
+            };
+        }
+
+        // A script holds the environment for as long as it runs; once that
+        // is too long, clients are told so instead of waiting for it. Links
+        // between replicas and their master are left alone, so no error ends
+        // up in the replication stream
+        if scripting::busy() && !self.allowed_when_busy(client) {
+            return self.respond(client, &reply::error(scripting::BUSY_ERROR));
+        }
 
-                if env.role() == "master" {
-                    stream
-                        .write_all(b"+OK\r\n")
-                        .map_err(|e| format!("Failed to write to stream: {}", e))?;
+        // RESP3 connections tell messages from replies, so they may run anything
+        if client.protocol() == 2
+            && client.subscription_count() > 0
+            && !self.kind.allowed_when_subscribed()
+        {
+            return self.respond(
+                client,
+                &reply::error(&format!(
+                    "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                    self.name().to_lowercase()
+                )),
+            );
+        }
 
-                    if let Err(e) = self.propagate() {
-                        eprintln!("Failed to propagate SET command: {}", e);
-                    }
-                }
+        if self.writes() && !client.is_master() {
+            let env = self.environment.lock().map_err(|e| e.to_string())?;
+            if env.role() == "slave" && env.config().replica_read_only {
+                drop(env);
+                client.fail_multi();
+                return self.respond(
+                    client,
+                    b"-READONLY You can't write against a read only replica.\r\n",
+                );
             }
-            RespCommand::GET => {
-                if self.data.len() < 2 {
-                    return Err("GET command requires at least 2 arguments".to_string());
-                }
-                let key = &self.data[1];
-                let mut env = self.environment.lock().map_err(|e| e.to_string())?;
-                match env.get(key) {
-                    Some(val) => {
-                        let response = format!("${}\r\n{}\r\n", val.len(), val);
-                        stream
-                            .write_all(response.as_bytes())
-                            .map_err(|e| format!("Failed to write to stream: {}", e))?;
+        }
+
+        if client.in_multi()
+            && !matches!(self.kind, RespCommand::MULTI | RespCommand::EXEC | RespCommand::DISCARD)
+        {
+            if !self.kind.allowed_in_multi() {
+                client.fail_multi();
+                return self.respond(
+                    client,
+                    &reply::error("ERR Command not allowed inside a transaction"),
+                );
+            }
+            client.queue(self.data.clone());
+            return self.respond(client, &reply::simple("QUEUED"));
+        }
+
+        match self.kind {
+            // Stopping a script cannot wait for the environment it keeps locked
+            RespCommand::SCRIPT | RespCommand::FUNCTION if self.is_script_kill() => {
+                self.respond(client, &scripting::kill())?;
+            }
+            RespCommand::HELLO => {
+                let response = self.hello(client)?;
+                self.respond(client, &response)?;
+            }
+            RespCommand::SUBSCRIBE
+            | RespCommand::PSUBSCRIBE
+            | RespCommand::SSUBSCRIBE
+            | RespCommand::UNSUBSCRIBE
+            | RespCommand::PUNSUBSCRIBE
+            | RespCommand::SUNSUBSCRIBE => {
+                let response = self.subscriptions(client)?;
+                self.respond(client, &response)?;
+            }
+            RespCommand::MULTI => {
+                let response = if client.in_multi() {
+                    reply::error("ERR MULTI calls can not be nested")
+                } else {
+                    client.begin_multi();
+                    reply::ok()
+                };
+                self.respond(client, &response)?;
+            }
+            RespCommand::EXEC => {
+                let response = match client.take_multi() {
+                    None => reply::error("ERR EXEC without MULTI"),
+                    Some((_, true)) => {
+                        self.unwatch(client)?;
+                        reply::error("EXECABORT Transaction discarded because of previous errors.")
                     }
-                    None => {
-                        stream
-                            .write_all(b"$-1\r\n")
-                            .map_err(|e| format!("Failed to write to stream: {}", e))?;
+                    Some((queued, false)) => self.exec(client, queued)?,
+                };
+                self.respond(client, &response)?;
+            }
+            RespCommand::DISCARD => {
+                let response = match client.take_multi() {
+                    Some(_) => {
+                        self.unwatch(client)?;
+                        reply::ok()
                     }
-                }
+                    None => reply::error("ERR DISCARD without MULTI"),
+                };
+                self.respond(client, &response)?;
             }
             RespCommand::INFO => {
                 if self.data.len() < 2 {
@@ -105,180 +155,980 @@ impl Resp2 {
                 let response: Vec<u8> = match section.to_uppercase().as_str() {
                     "REPLICATION" => {
                         let env = self.environment.lock().map_err(|e| e.to_string())?;
+                        let backlog = env.backlog();
+                        let mut slaves = format!("connected_slaves:{}\r\n", env.slaves().len());
+                        for (i, slave) in env.slaves().iter().enumerate() {
+                            slaves.push_str(&format!(
+                                "slave{}:ip={},port={},state=online,offset={},lag={}\r\n",
+                                i,
+                                slave.ip(),
+                                slave.listening_port(),
+                                slave.offset(),
+                                slave.lag()
+                            ));
+                        }
+                        let mut master = String::new();
+                        if let Some((host, port)) = env.master_host() {
+                            master = format!(
+                                "master_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\nmaster_sync_in_progress:{}\r\nslave_repl_offset:{}\r\nslave_read_only:{}\r\n",
+                                host,
+                                port,
+                                if env.repl_state() == ReplState::Connected { "up" } else { "down" },
+                                (env.repl_state() == ReplState::Transfer) as u8,
+                                env.master_repl_offset(),
+                                env.config().replica_read_only as u8
+                            );
+                        }
                         let content = format!(
-                            "role:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}",
+                            "role:{}\r\n{}{}master_replid:{}\r\nmaster_replid2:{}\r\nmaster_repl_offset:{}\r\nsecond_repl_offset:{}\r\nrepl_backlog_active:1\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}",
                             env.role(),
+                            master,
+                            slaves,
                             env.master_replid(),
-                            env.master_repl_offset()
+                            env.master_replid2(),
+                            env.master_repl_offset(),
+                            env.second_replid_offset().map_or(-1, |o| o as i64),
+                            backlog.size(),
+                            backlog.first_offset(),
+                            backlog.histlen()
                         );
 
                         format!("${}\r\n{}\r\n", content.len(), content)
                             .as_bytes()
                             .to_vec()
                     }
+                    "KEYSPACE" => {
+                        let env = self.environment.lock().map_err(|e| e.to_string())?;
+                        let content = env
+                            .databases()
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, db)| !db.is_empty())
+                            .map(|(index, db)| {
+                                format!(
+                                    "db{}:keys={},expires={},avg_ttl=0",
+                                    index,
+                                    db.len(),
+                                    db.expires()
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\r\n");
+
+                        format!("${}\r\n{}\r\n", content.len(), content)
+                            .as_bytes()
+                            .to_vec()
+                    }
+                    "MEMORY" => {
+                        let env = self.environment.lock().map_err(|e| e.to_string())?;
+                        let content = format!(
+                            "used_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\nevicted_keys:{}",
+                            env.used_memory(),
+                            env.config().maxmemory,
+                            env.config().maxmemory_policy.name(),
+                            env.evicted_keys()
+                        );
+
+                        format!("${}\r\n{}\r\n", content.len(), content)
+                            .as_bytes()
+                            .to_vec()
+                    }
+                    "PERSISTENCE" => {
+                        let env = self.environment.lock().map_err(|e| e.to_string())?;
+                        let mut content = format!(
+                            "aof_enabled:{}\r\naof_rewrite_in_progress:{}\r\naof_last_bgrewrite_status:{}",
+                            env.aof().is_some() as u8,
+                            env.aof().is_some_and(|aof| aof.rewrite_in_progress()) as u8,
+                            if env.aof().is_none_or(|aof| aof.last_rewrite_ok()) { "ok" } else { "err" }
+                        );
+                        if let Some(aof) = env.aof() {
+                            content.push_str(&format!(
+                                "\r\naof_current_size:{}\r\naof_base_size:{}",
+                                aof.current_size(),
+                                aof.base_size()
+                            ));
+                        }
+
+                        format!("${}\r\n{}\r\n", content.len(), content)
+                            .as_bytes()
+                            .to_vec()
+                    }
                     _ => {
                         return Err(format!("Unknown INFO section: '{}'", section));
                     }
                 };
 
-                stream
-                    .write_all(&response)
-                    .map_err(|e| format!("Failed to write to stream: {}", e))?;
+                self.respond(client, &response)?;
+            }
+            RespCommand::REPLCONF => {
+                let subcommand = self
+                    .data
+                    .get(1)
+                    .map(|s| s.to_lowercase())
+                    .unwrap_or_default();
+                match subcommand.as_str() {
+                    "getack" => {
+                        let env = self.environment.lock().map_err(|e| e.to_string())?;
+                        let mut ack = Resp2::new(self.environment.clone());
+                        ack.set_data(vec![
+                            "REPLCONF".to_string(),
+                            "ACK".to_string(),
+                            env.master_repl_offset().to_string(),
+                        ]);
+                        let ack_payload: Vec<u8> = ack.serialize_array();
+                        // GETACK is the only command a replica answers on its master link
+                        Self::send(client, &ack_payload)?;
+                    }
+                    "ack" => {
+                        // Replicas never expect a reply to their acknowledgements,
+                        // so one without a usable offset is just ignored
+                        let Some(offset) = self.data.get(2).and_then(|o| o.parse::<u64>().ok())
+                        else {
+                            return Ok(());
+                        };
+                        let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+                        env.acknowledge_slave(client.id(), offset);
+                    }
+                    "listening-port" => {
+                        let port = self
+                            .data
+                            .get(2)
+                            .and_then(|p| p.parse::<u16>().ok())
+                            .ok_or("REPLCONF listening-port requires a valid port")?;
+                        client.set_listening_port(port);
+                        self.respond(client, b"+OK\r\n")?;
+                    }
+                    "capa" => {
+                        // REPLCONF capa <CAPABILITY> [capa <CAPABILITY> ...]
+                        let eof = self.data[1..]
+                            .chunks(2)
+                            .any(|pair| pair.last().is_some_and(|c| c.eq_ignore_ascii_case("eof")));
+                        if eof {
+                            client.set_eof_capable();
+                        }
+                        self.respond(client, b"+OK\r\n")?;
+                    }
+                    _ => {
+                        self.respond(client, b"+OK\r\n")?;
+                    }
+                }
             }
-            RespCommand::INTITIALIZE => {
+            RespCommand::PSYNC => {
                 let mut env = self.environment.lock().map_err(|e| e.to_string())?;
 
-                // PING
-                let mut ping = Resp2::new(self.environment.clone());
-                ping.set_kind(RespCommand::PING);
-                ping.set_data(vec!["PING".to_string()]);
-                let ping_payload: Vec<u8> = ping.serialize_array();
-                stream
-                    .write_all(&ping_payload)
-                    .map_err(|e| format!("Failed to send handshake to master: {}", e))?;
-
-                // PONG
-                if self.read_master(&mut stream).is_err() {
-                    return Err("Failed to read from master".to_string());
-                }
-
-                // REPLCONF listening-port <PORT>
-                let mut replconf = Resp2::new(self.environment.clone());
-                replconf.set_kind(RespCommand::REPLCONF);
-                replconf.set_data(vec![
-                    "REPLCONF".to_string(),
-                    "listening-port".to_string(),
-                    env.port().to_string(),
-                ]);
-                let replconf_payload: Vec<u8> = replconf.serialize_array();
-                stream
-                    .write_all(&replconf_payload)
-                    .map_err(|e| format!("Failed to send REPLCONF to master: {}", e))?;
-
-                // OK
-                if self.read_master(&mut stream).is_err() {
-                    return Err("Failed to read from master".to_string());
-                }
-
-                // REPLCONF capa psync2
-                let mut replconf_capa = Resp2::new(self.environment.clone());
-                replconf_capa.set_kind(RespCommand::REPLCONF);
-                replconf_capa.set_data(vec![
-                    "REPLCONF".to_string(),
-                    "capa".to_string(),
-                    "psync2".to_string(),
-                ]);
-                let replconf_capa_payload: Vec<u8> = replconf_capa.serialize_array();
-                stream
-                    .write_all(&replconf_capa_payload)
-                    .map_err(|e| format!("Failed to send REPLCONF capa to master: {}", e))?;
-
-                // OK
-                if self.read_master(&mut stream).is_err() {
-                    return Err("Failed to read from master".to_string());
-                }
-
-                // PSYNC <REPLID> <OFFSET>
-                let mut psync = Resp2::new(self.environment.clone());
-                psync.set_kind(RespCommand::PSYNC);
-                psync.set_data(vec!["PSYNC".to_string(), "?".to_string(), "-1".to_string()]);
-
-                let psync_payload: Vec<u8> = psync.serialize_array();
-                stream
-                    .write_all(&psync_payload)
-                    .map_err(|e| format!("Failed to send PSYNC to master: {}", e))?;
-
-                // Update master_replid and master_repl_offset
-                let mut buffer = vec![0; 1024];
-                let n = stream
-                    .read(&mut buffer)
-                    .map_err(|e| format!("Failed to read from stream: {}", e))?;
-                if n == 0 {
-                    return Err("Connection closed by master".to_string());
-                }
-                let end = buffer[..n].windows(2).position(|w| w == b"\r\n");
-                if let Some(pos) = end {
-                    let line = &buffer[..pos];
-                    let header = std::str::from_utf8(line)
-                        .map_err(|e| format!("Header is not valid UTF-8: {}", e))?;
-
-                    if !header.starts_with("+FULLRESYNC") {
-                        return Err(format!("Unexpected response from master: '{}'", header));
+                // A replica can only serve the history it receives from its master
+                if env.role() == "slave" && env.repl_state() != ReplState::Connected {
+                    drop(env);
+                    return self.respond(
+                        client,
+                        b"-NOMASTERLINK Can't SYNC while not connected with my master\r\n",
+                    );
+                }
+
+                let requested = match (self.data.get(1), self.data.get(2)) {
+                    (Some(replid), Some(offset)) => offset
+                        .parse::<u64>()
+                        .ok()
+                        .and_then(|offset| env.try_partial_resync(replid, offset).map(|d| (offset, d))),
+                    _ => None,
+                };
+
+                // Everything is queued on the replica's own output buffer so a
+                // slow replica never stalls the server while it holds the lock
+                let outbox = client.outbox().ok_or("PSYNC requires a connection")?.clone();
+                client.set_replica();
+                let mut slave = SlaveConnection::new(
+                    client.id(),
+                    outbox,
+                    0,
+                    client.listening_port().unwrap_or(0),
+                );
+
+                match requested {
+                    Some((offset, missing)) => {
+                        let mut payload =
+                            format!("+CONTINUE {}\r\n", env.master_replid()).into_bytes();
+                        payload.extend_from_slice(&missing);
+
+                        slave.set_offset(offset - 1);
+                        let limit = env.config().replica_output_buffer_limit;
+                        if slave.send(&payload, &limit) {
+                            env.add_slave(slave);
+                        }
                     }
+                    None => replication::full_sync(
+                        self.environment.clone(),
+                        &mut env,
+                        slave,
+                        client.eof_capable(),
+                    ),
+                }
+            }
+            RespCommand::WAIT => {
+                if self.data.len() < 3 {
+                    return Err("WAIT command requires 2 arguments".to_string());
+                }
+                // Writes to a replica are never propagated, so there is nothing to wait for
+                if self.environment.lock().map_err(|e| e.to_string())?.role() == "slave" {
+                    return self.respond(
+                        client,
+                        &reply::error("ERR WAIT cannot be used with replica instances"),
+                    );
+                }
+                let (Ok(numreplicas), Ok(timeout)) =
+                    (self.data[1].parse::<i64>(), self.data[2].parse::<i64>())
+                else {
+                    return self.respond(client, &reply::not_an_integer());
+                };
+                if timeout < 0 {
+                    return self.respond(client, &reply::error("ERR timeout is negative"));
+                }
+                // Asking for no replica at all (or fewer) is always satisfied
+                let numreplicas = numreplicas.max(0) as usize;
+                let timeout = timeout as u64;
+
+                let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+                let target = client.last_write_offset();
+
+                if target > 0 && env.count_acknowledged(target) < numreplicas {
+                    // Ask every replica for its offset right away instead of
+                    // waiting for the next periodic acknowledgement
+                    let mut getack = Resp2::new(self.environment.clone());
+                    getack.set_data(vec![
+                        "REPLCONF".to_string(),
+                        "GETACK".to_string(),
+                        "*".to_string(),
+                    ]);
+                    let getack_payload: Vec<u8> = getack.serialize_array();
+                    env.propagate(&getack_payload);
+
+                    let signal = env.ack_signal();
+                    let deadline = (timeout > 0)
+                        .then(|| SystemTime::now() + Duration::from_millis(timeout));
 
-                    let parts: Vec<&str> = header.split_whitespace().collect();
-                    if parts.len() < 3 {
-                        return Err("Invalid FULLRESYNC response from master".to_string());
+                    while env.count_acknowledged(target) < numreplicas {
+                        env = match deadline {
+                            Some(deadline) => {
+                                let remaining = match deadline.duration_since(SystemTime::now()) {
+                                    Ok(remaining) if !remaining.is_zero() => remaining,
+                                    _ => break,
+                                };
+                                signal
+                                    .wait_timeout(env, remaining)
+                                    .map_err(|e| e.to_string())?
+                                    .0
+                            }
+                            None => signal.wait(env).map_err(|e| e.to_string())?,
+                        };
                     }
-                    let replid = parts[1].to_string();
-                    let offset = parts[2]
-                        .parse::<u64>()
-                        .map_err(|_| "Invalid offset in FULLRESYNC response".to_string())?;
+                }
 
-                    env.set_master_replid(replid);
-                    env.set_master_repl_offset(offset);
+                let acknowledged = if target == 0 {
+                    env.slaves().len()
                 } else {
-                    return Err("No CRLF found in FULLRESYNC response".to_string());
-                }
+                    env.count_acknowledged(target)
+                };
+                let response = format!(":{}\r\n", acknowledged);
+                self.respond(client, response.as_bytes())?;
             }
-            RespCommand::REPLCONF => {
-                stream
-                    .write_all(b"+OK\r\n")
-                    .map_err(|e| format!("Failed to write to stream: {}", e))?;
+            RespCommand::REPLICAOF => {
+                if self.data.len() < 3 {
+                    return Err("REPLICAOF command requires 2 arguments".to_string());
+                }
+                let (host, port) = (&self.data[1], &self.data[2]);
+
+                if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                    let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+                    env.unset_master();
+                    drop(env);
+                    self.respond(client, b"+OK\r\n")?;
+                    return Ok(());
+                }
+
+                let port = match port.parse::<u16>() {
+                    Ok(port) if port > 0 => port,
+                    _ => {
+                        return self.respond(client, b"-ERR Invalid master port\r\n");
+                    }
+                };
+                let target = (host.clone(), port);
+
+                let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+                if env.master_host() == Some(&target) {
+                    drop(env);
+                    return self.respond(client, b"+OK Already connected to specified master\r\n");
+                }
+                env.set_master(target);
+                drop(env);
+
+                replication::start(self.environment.clone());
+                self.respond(client, b"+OK\r\n")?;
             }
-            RespCommand::PSYNC => {
+            RespCommand::BGREWRITEAOF => {
                 let mut env = self.environment.lock().map_err(|e| e.to_string())?;
-                let response = format!(
-                    "+FULLRESYNC {} {}\r\n",
-                    "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-                    0
-                );
-                stream
-                    .write_all(response.as_bytes())
-                    .map_err(|e| format!("Failed to write to stream: {}", e))?;
-
-                // Send empty RDB
-                let rdb_base64 = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
-                let rdb_data = STANDARD
-                    .decode(rdb_base64)
-                    .map_err(|e| format!("Failed to decode RDB base64: {}", e))?;
-
-                let header = format!("${}\r\n", rdb_data.len());
-                stream
-                    .write_all(header.as_bytes())
-                    .map_err(|e| format!("Failed to write RDB header: {}", e))?;
-
-                // 4. Send raw RDB contents
-                stream
-                    .write_all(&rdb_data)
-                    .map_err(|e| format!("Failed to write RDB body: {}", e))?;
-
-                // 5. Add slave to environment
-                let wrapped_stream = Arc::new(Mutex::new(
-                    stream
-                        .try_clone()
-                        .map_err(|e| format!("Failed to clone stream: {}", e))?,
-                ));
-
-                env.add_slave(wrapped_stream, 0);
+                let response = match aof::rewrite(&self.environment, &mut env) {
+                    Ok(()) => reply::simple("Background append only file rewriting started"),
+                    Err(e) => reply::error(&format!("ERR {}", e)),
+                };
+                drop(env);
+                self.respond(client, &response)?;
             }
             _ => {
-                let err = b"-ERR unknown command\r\n";
-                stream
-                    .write_all(err)
-                    .map_err(|e| format!("Failed to write to stream: {}", e))?;
+                let response = self.dispatch(client)?;
+                self.respond(client, &response)?;
             }
         }
 
         Ok(())
     }
 
-    pub fn set_kind(&mut self, kind: RespCommand) {
-        self.kind = kind;
+    /// Runs a dataset command and replicates its effects. Write commands that
+    /// changed the dataset are forwarded in their canonical form, together with
+    /// any side effects (such as expired keys) recorded while executing.
+    fn dispatch(&mut self, client: &mut Client) -> Result<Vec<u8>, String> {
+        let environment = self.environment.clone();
+        let mut env = environment.lock().map_err(|e| e.to_string())?;
+        env.set_current_client(client.id());
+
+        if let Some(oom) = Self::make_room(&mut env, client, self.kind.denies_oom()) {
+            return Ok(oom);
+        }
+
+        // Whatever the command did before failing is still replicated
+        let response = self.run(&mut env, client);
+        Self::flush_pending(&mut env, client);
+
+        response
+    }
+
+    /// Runs the commands queued since MULTI under a single lock, so no other
+    /// client observes the dataset halfway through. Their effects are
+    /// replicated together, wrapped in MULTI/EXEC.
+    fn exec(&mut self, client: &mut Client, queued: Vec<Vec<String>>) -> Result<Vec<u8>, String> {
+        let environment = self.environment.clone();
+        let mut env = environment.lock().map_err(|e| e.to_string())?;
+        env.set_current_client(client.id());
+
+        let invalidated = env.watch_invalidated(client.id(), client.watched());
+        env.unwatch(client.id(), client.take_watched());
+        if invalidated {
+            return Ok(reply::null_array());
+        }
+
+        let mut commands: Vec<Resp2> = queued
+            .into_iter()
+            .map(|data| {
+                let mut command = Resp2::new(environment.clone());
+                command.set_kind(RespCommand::from_str(&data[0]));
+                command.set_data(data);
+                command
+            })
+            .collect();
+
+        let denies_oom = commands.iter().any(|command| command.kind.denies_oom());
+        if let Some(oom) = Self::make_room(&mut env, client, denies_oom) {
+            return Ok(oom);
+        }
+
+        // A command failing partway leaves the earlier ones applied, and their
+        // effects must not leak into whatever this client runs next
+        let replies: Result<Vec<_>, _> = commands
+            .iter_mut()
+            .map(|command| command.run(&mut env, client))
+            .collect();
+        Self::flush_pending(&mut env, client);
+
+        Ok(reply::array(replies?))
     }
 
-    pub fn set_literal(&mut self, literal: Vec<u8>) {
-        self.literal = literal;
+    /// Stops watching every key, once the transaction ended.
+    fn unwatch(&self, client: &mut Client) -> Result<(), String> {
+        let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+        env.unwatch(client.id(), client.take_watched());
+        Ok(())
+    }
+
+    /// Evicts keys until the dataset fits in `maxmemory`, returning the error to
+    /// reply with when it doesn't and the command may grow it (`denies_oom`).
+    fn make_room(env: &mut Environment, client: &mut Client, denies_oom: bool) -> Option<Vec<u8>> {
+        // Replicas never evict on their own, they apply the master's DELs
+        if env.role() != "master" || client.is_master() {
+            return None;
+        }
+
+        let fits = perform_evictions(env);
+        Self::flush_pending(env, client);
+
+        (!fits && denies_oom)
+            .then(|| reply::error("OOM command not allowed when used memory > 'maxmemory'."))
+    }
+
+    /// Writes the effects recorded so far to the AOF and the replicas.
+    fn flush_pending(env: &mut Environment, client: &mut Client) {
+        let pending = env.take_pending();
+        if pending.is_empty() {
+            return;
+        }
+        env.feed_aof(&pending);
+
+        if env.role() == "master" && !client.is_master() {
+            env.propagate_effects(&pending);
+            client.set_last_write_offset(env.master_repl_offset());
+        }
+    }
+
+    /// Runs a command called by a Lua script, returning its reply. Scripts
+    /// running `read_only` may not call commands that write.
+    pub fn call(
+        &mut self,
+        env: &mut Environment,
+        client: &mut Client,
+        read_only: bool,
+    ) -> Result<Vec<u8>, String> {
+        if matches!(self.kind, RespCommand::UNDEFINED) {
+            return Ok(reply::error("ERR Unknown Redis command called from script"));
+        }
+        if !self.kind.accepts(self.data.len()) {
+            return Ok(reply::error(
+                "ERR Wrong number of args calling Redis command from script",
+            ));
+        }
+        if !self.kind.allowed_in_script() {
+            return Ok(reply::error("ERR This Redis command is not allowed from script"));
+        }
+        if read_only && self.writes() {
+            return Ok(reply::error(
+                "ERR Write commands are not allowed from read-only scripts.",
+            ));
+        }
+        if self.writes() && env.role() == "slave" && env.config().replica_read_only {
+            return Ok(reply::error("READONLY You can't write against a read only replica."));
+        }
+        let maxmemory = env.config().maxmemory;
+        if self.kind.denies_oom() && maxmemory > 0 && env.used_memory() > maxmemory {
+            return Ok(reply::error(
+                "OOM command not allowed when used memory > 'maxmemory'.",
+            ));
+        }
+
+        self.run(env, client)
+    }
+
+    /// Runs SUBSCRIBE, PSUBSCRIBE, SSUBSCRIBE or their UNSUBSCRIBE counterparts, which
+    /// reply once per channel or pattern. Without arguments, unsubscribing
+    /// drops every subscription of that kind.
+    fn subscriptions(&self, client: &mut Client) -> Result<Vec<u8>, String> {
+        let (kind, subscribing) = match self.kind {
+            RespCommand::SUBSCRIBE => (Subscription::Channel, true),
+            RespCommand::PSUBSCRIBE => (Subscription::Pattern, true),
+            RespCommand::SSUBSCRIBE => (Subscription::Shard, true),
+            RespCommand::UNSUBSCRIBE => (Subscription::Channel, false),
+            RespCommand::PUNSUBSCRIBE => (Subscription::Pattern, false),
+            _ => (Subscription::Shard, false),
+        };
+        let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+        let resp3 = client.protocol() == 3;
+        if let Some(outbox) = client.outbox() {
+            env.pubsub_mut().attach(client.id(), outbox, resp3);
+        }
+        // RESP3 clients get confirmations as pushes, like the messages
+        let frame = |items| match resp3 {
+            true => reply::push(items),
+            false => reply::array(items),
+        };
+
+        let names = match self.data.len() {
+            1 => client.subscriptions(kind).to_vec(),
+            _ => self.data[1..].to_vec(),
+        };
+
+        let mut response = Vec::new();
+        if names.is_empty() {
+            response.extend(frame(vec![
+                reply::bulk(kind.unsubscribe_name()),
+                reply::null(),
+                reply::integer(client.subscription_count_of(kind) as i64),
+            ]));
+        }
+        for name in &names {
+            if subscribing && client.subscribe(kind, name) {
+                env.pubsub_mut().subscribe(kind, client.id(), name);
+            } else if !subscribing && client.unsubscribe(kind, name) {
+                env.pubsub_mut().unsubscribe(kind, client.id(), name);
+            }
+
+            let confirmation = match subscribing {
+                true => kind.subscribe_name(),
+                false => kind.unsubscribe_name(),
+            };
+            response.extend(frame(vec![
+                reply::bulk(confirmation),
+                reply::bulk(name),
+                reply::integer(client.subscription_count_of(kind) as i64),
+            ]));
+        }
+        Ok(response)
+    }
+
+    /// Remembers the keys a tracking client read, so it is told when they
+    /// change. CLIENT CACHING only applies to the command that follows it.
+    fn track_reads(&self, env: &mut Environment, client: &mut Client) {
+        let caching = client.caching();
+        if !self.is_client_caching() {
+            client.set_caching(None);
+        }
+
+        let Some(options) = env.tracking().options(client.id()) else {
+            return;
+        };
+        let track = match (options.optin, options.optout) {
+            (true, _) => caching == Some(true),
+            (_, true) => caching != Some(false),
+            _ => true,
+        };
+        if options.bcast || !track {
+            return;
+        }
+        for key in self.read_keys() {
+            env.tracking_mut().remember(client.id(), key);
+        }
+    }
+
+    /// Keys a read-only command looked up.
+    fn read_keys(&self) -> &[String] {
+        match self.kind {
+            RespCommand::GET => &self.data[1..2],
+            RespCommand::OBJECT if self.data.len() > 2 => &self.data[2..3],
+            _ => &[],
+        }
+    }
+
+    fn is_client_caching(&self) -> bool {
+        matches!(self.kind, RespCommand::CLIENT) && self.data[1].eq_ignore_ascii_case("CACHING")
+    }
+
+    /// HELLO [protover]: switches the connection to RESP2 or RESP3. RESP3
+    /// connections can be pushed messages at any time, so they are registered
+    /// with Pub/Sub right away.
+    fn hello(&self, client: &mut Client) -> Result<Vec<u8>, String> {
+        if let Some(version) = self.data.get(1) {
+            let protocol = match version.parse::<i64>() {
+                Ok(protocol @ (2 | 3)) => protocol as u8,
+                Ok(_) => return Ok(reply::error("NOPROTO unsupported protocol version")),
+                Err(_) => {
+                    return Ok(reply::error(
+                        "ERR Protocol version is not an integer or out of range",
+                    ))
+                }
+            };
+            if let Some(option) = self.data.get(2) {
+                return Ok(reply::error(&format!(
+                    "ERR Syntax error in HELLO option '{}'",
+                    option
+                )));
+            }
+
+            client.set_protocol(protocol);
+        }
+
+        let mut env = self.environment.lock().map_err(|e| e.to_string())?;
+        let resp3 = client.protocol() == 3;
+        if let Some(outbox) = client.outbox() {
+            env.pubsub_mut().attach(client.id(), outbox, resp3);
+        }
+
+        let role = match env.role() {
+            "master" => "master",
+            _ => "replica",
+        };
+        Ok(reply::map(
+            vec![
+                ("server", reply::bulk("redis")),
+                ("version", reply::bulk("7.2.0")),
+                ("proto", reply::integer(client.protocol() as i64)),
+                ("id", reply::integer(client.id() as i64)),
+                ("mode", reply::bulk("standalone")),
+                ("role", reply::bulk(role)),
+                ("modules", reply::array(Vec::new())),
+            ],
+            resp3,
+        ))
+    }
+
+    /// Whether the command modifies the dataset. Only some FUNCTION
+    /// subcommands do, as the libraries are part of it.
+    fn writes(&self) -> bool {
+        match self.kind {
+            RespCommand::FUNCTION => ["LOAD", "DELETE", "FLUSH", "RESTORE"]
+                .iter()
+                .any(|sub| self.data[1].eq_ignore_ascii_case(sub)),
+            _ => self.kind.is_write(),
+        }
+    }
+
+    fn allowed_when_busy(&self, client: &Client) -> bool {
+        client.is_master()
+            || client.is_replica()
+            || matches!(self.kind, RespCommand::REPLCONF)
+            || self.is_script_kill()
+    }
+
+    fn is_script_kill(&self) -> bool {
+        matches!(self.kind, RespCommand::SCRIPT | RespCommand::FUNCTION)
+            && self.data.len() == 2
+            && self.data[1].eq_ignore_ascii_case("KILL")
+    }
+
+    /// Applies a command read back from the append-only file at startup.
+    pub fn replay(&mut self, env: &mut Environment, client: &mut Client) -> Result<(), String> {
+        if !self.kind.accepts(self.data.len()) {
+            return Err(format!(
+                "Invalid command '{}' in append-only file",
+                self.data.first().cloned().unwrap_or_default()
+            ));
+        }
+
+        self.run(env, client)?;
+        env.take_pending();
+        Ok(())
+    }
+
+    /// Executes the command and queues its canonical form for propagation
+    /// when it changed the dataset.
+    fn run(&mut self, env: &mut Environment, client: &mut Client) -> Result<Vec<u8>, String> {
+        let dirty = env.dirty();
+        self.rewritten = None;
+        let response = self.execute(env, client)?;
+
+        if self.writes() && env.dirty() > dirty {
+            let args = self.rewritten.take().unwrap_or_else(|| self.data.clone());
+            env.also_propagate(client.db(), args);
+        }
+
+        self.track_reads(env, client);
+        Ok(response)
+    }
+
+    /// Executes a command against an already locked environment, returning its reply.
+    fn execute(&mut self, env: &mut Environment, client: &mut Client) -> Result<Vec<u8>, String> {
+        let db = client.db();
+        let response = match self.kind {
+            RespCommand::PING if client.protocol() == 2 && client.subscription_count() > 0 => {
+                reply::array(vec![
+                    reply::bulk("pong"),
+                    reply::bulk(self.data.get(1).map_or("", |message| message.as_str())),
+                ])
+            }
+            RespCommand::PING if self.data.len() > 1 => reply::bulk(&self.data[1]),
+            RespCommand::PING => reply::simple("PONG"),
+            RespCommand::ECHO => reply::simple(&self.data[1]),
+            RespCommand::SET => {
+                let key = self.data[1].clone();
+                let value = self.data[2].clone();
+
+                let mut expiry: Option<SystemTime> = None;
+                let (mut nx, mut xx, mut keep_ttl, mut get) = (false, false, false, false);
+
+                let mut i = 3;
+                while i < self.data.len() {
+                    let option = self.data[i].to_uppercase();
+                    match option.as_str() {
+                        "NX" if !xx => nx = true,
+                        "XX" if !nx => xx = true,
+                        "GET" => get = true,
+                        "KEEPTTL" if expiry.is_none() => keep_ttl = true,
+                        "EX" | "PX" | "EXAT" | "PXAT"
+                            if expiry.is_none() && !keep_ttl && i + 1 < self.data.len() =>
+                        {
+                            i += 1;
+                            let amount = match self.data[i].parse::<u64>() {
+                                Ok(amount) if amount > 0 => amount,
+                                Ok(_) => {
+                                    return Ok(reply::error(
+                                        "ERR invalid expire time in 'set' command",
+                                    ))
+                                }
+                                Err(_) => return Ok(reply::not_an_integer()),
+                            };
+                            // Checked, so an absurd expiration is refused rather
+                            // than overflowing the clock
+                            let millis = match option.as_str() {
+                                "EX" | "EXAT" => amount.checked_mul(1000),
+                                _ => Some(amount),
+                            };
+                            let base = match option.as_str() {
+                                "EX" | "PX" => SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .map_or(0, |d| d.as_millis() as u64),
+                                _ => 0,
+                            };
+                            let Some(at) = millis
+                                .and_then(|millis| millis.checked_add(base))
+                                .filter(|at| *at <= i64::MAX as u64)
+                            else {
+                                return Ok(reply::error(
+                                    "ERR invalid expire time in 'set' command",
+                                ));
+                            };
+                            expiry = Some(UNIX_EPOCH + Duration::from_millis(at));
+                        }
+                        _ => return Ok(reply::syntax_error()),
+                    }
+                    i += 1;
+                }
+
+                let previous = env.get(db, &key).map(str::to_string);
+                if (nx && previous.is_some()) || (xx && previous.is_none()) {
+                    return Ok(match (get, previous) {
+                        (true, Some(previous)) => reply::bulk(&previous),
+                        _ => reply::null(),
+                    });
+                }
+
+                let expires = expiry.is_some();
+                if keep_ttl {
+                    expiry = env.expiry(db, &key);
+                }
+                env.set(db, key.clone(), value.clone(), expiry);
+                if expires {
+                    env.notify(NOTIFY_GENERIC, "expire", db, &key);
+                }
+
+                // Relative expirations are replicated as absolute ones so that
+                // replicas and the backlog expire the key at the same instant
+                let mut rewritten = vec!["SET".to_string(), key, value];
+                if let Some(expiry) = expiry {
+                    let at = expiry
+                        .duration_since(UNIX_EPOCH)
+                        .map_err(|e| e.to_string())?
+                        .as_millis();
+                    rewritten.push("PXAT".to_string());
+                    rewritten.push(at.to_string());
+                }
+                self.rewritten = Some(rewritten);
+
+                match (get, previous) {
+                    (true, Some(previous)) => reply::bulk(&previous),
+                    (true, None) => reply::null(),
+                    _ => reply::ok(),
+                }
+            }
+            RespCommand::GET => match env.get(db, &self.data[1]) {
+                Some(val) => reply::bulk(val),
+                None => {
+                    env.notify(NOTIFY_KEY_MISS, "keymiss", db, &self.data[1]);
+                    reply::null()
+                }
+            },
+            RespCommand::DEL => {
+                let removed = self.data[1..].iter().filter(|key| env.del(db, key)).count();
+                reply::integer(removed as i64)
+            }
+            RespCommand::SELECT => match parse_db_index(env, &self.data[1]) {
+                Ok(index) => {
+                    client.select(index);
+                    reply::ok()
+                }
+                Err(e) => e,
+            },
+            RespCommand::MOVE => {
+                let dst = match parse_db_index(env, &self.data[2]) {
+                    Ok(dst) => dst,
+                    Err(e) => return Ok(e),
+                };
+                if dst == db {
+                    return Ok(reply::error(
+                        "ERR source and destination objects are the same",
+                    ));
+                }
+                reply::integer(env.move_key(db, &self.data[1], dst) as i64)
+            }
+            RespCommand::SWAPDB => {
+                let first = match self.data[1].parse::<usize>() {
+                    Ok(first) => first,
+                    Err(_) => return Ok(reply::error("ERR invalid first DB index")),
+                };
+                let second = match self.data[2].parse::<usize>() {
+                    Ok(second) => second,
+                    Err(_) => return Ok(reply::error("ERR invalid second DB index")),
+                };
+                if first.max(second) >= env.databases().len() {
+                    return Ok(reply::error("ERR DB index is out of range"));
+                }
+
+                env.swap_db(first, second);
+                reply::ok()
+            }
+            RespCommand::FLUSHDB | RespCommand::FLUSHALL => {
+                // Flushing always happens synchronously, whichever mode is asked for
+                if let Some(mode) = self.data.get(1) {
+                    let mode = mode.to_lowercase();
+                    if self.data.len() > 2 || (mode != "sync" && mode != "async") {
+                        return Ok(reply::syntax_error());
+                    }
+                }
+
+                match self.kind {
+                    RespCommand::FLUSHDB => env.flush_db(db),
+                    _ => env.flush_all(),
+                }
+                reply::ok()
+            }
+            RespCommand::DBSIZE => reply::integer(env.db(db).len() as i64),
+            RespCommand::KEYS => {
+                let keys = env.keys(db, &self.data[1]);
+                reply::array(keys.iter().map(|key| reply::bulk(key)).collect())
+            }
+            RespCommand::SCAN => {
+                let cursor = match self.data[1].parse::<u64>() {
+                    Ok(cursor) => cursor,
+                    Err(_) => return Ok(reply::error("ERR invalid cursor")),
+                };
+                let options = match ScanOptions::parse(&self.data[2..], true) {
+                    Ok(options) => options,
+                    Err(e) => return Ok(e),
+                };
+
+                let (next, keys) = env.scan(db, cursor, options.count);
+                let mut matched = Vec::new();
+                for key in keys {
+                    if !options.matches(&key) {
+                        continue;
+                    }
+                    // Expired keys are deleted on the way, as any lookup would
+                    match env.key_type(db, &key) {
+                        Some(kind) if options.kind.as_ref().is_none_or(|k| k == kind) => {
+                            matched.push(reply::bulk(&key))
+                        }
+                        _ => {}
+                    }
+                }
+
+                reply::array(vec![reply::bulk(&next.to_string()), reply::array(matched)])
+            }
+            RespCommand::RANDOMKEY => match env.random_key(db) {
+                Some(key) => reply::bulk(&key),
+                None => reply::null(),
+            },
+            RespCommand::OBJECT => {
+                let subcommand = self.data[1].to_uppercase();
+                if subcommand == "HELP" {
+                    let lines = [
+                        "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                        "ENCODING <key>",
+                        "    Return the kind of internal representation used in order to store the value",
+                        "    associated with a <key>.",
+                        "FREQ <key>",
+                        "    Return the access frequency index of the <key>. The returned integer is",
+                        "    proportional to the logarithm of the recent access frequency of the key.",
+                        "IDLETIME <key>",
+                        "    Return the idle time of the <key>, that is the approximated number of",
+                        "    seconds elapsed since the last access to the key.",
+                        "REFCOUNT <key>",
+                        "    Return the number of references of the value associated with the specified",
+                        "    <key>.",
+                        "HELP",
+                        "    Print this help.",
+                    ];
+                    return Ok(reply::array(lines.iter().map(|line| reply::simple(line)).collect()));
+                }
+                if !["ENCODING", "FREQ", "IDLETIME", "REFCOUNT"].contains(&subcommand.as_str()) {
+                    return Ok(reply::error(&format!(
+                        "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+                        self.data[1]
+                    )));
+                }
+                if self.data.len() != 3 {
+                    return Ok(reply::wrong_arity(&format!("object|{}", subcommand)));
+                }
+
+                let policy = env.config().maxmemory_policy;
+                let evicts = env.config().maxmemory > 0;
+                let decay_time = env.config().lfu_decay_time;
+                let Some(entry) = env.entry(db, &self.data[2]) else {
+                    return Ok(reply::null());
+                };
+
+                match subcommand.as_str() {
+                    "ENCODING" => reply::bulk(entry.encoding()),
+                    "FREQ" if !policy.is_lfu() => reply::error(
+                        "ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.",
+                    ),
+                    "FREQ" => reply::integer(entry.frequency(decay_time) as i64),
+                    "IDLETIME" if policy.is_lfu() => reply::error(
+                        "ERR An LFU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.",
+                    ),
+                    "IDLETIME" => reply::integer(entry.idle_time().as_secs() as i64),
+                    // Shared integers are not used once keys carry their own
+                    // access metadata for an LRU or LFU policy
+                    _ if entry.is_shared() && !(evicts && (policy.is_lru() || policy.is_lfu())) => {
+                        reply::integer(i32::MAX as i64)
+                    }
+                    _ => reply::integer(1),
+                }
+            }
+            RespCommand::WATCH => {
+                for key in &self.data[1..] {
+                    if client.watch(db, key) {
+                        env.watch(client.id(), db, key);
+                    }
+                }
+                reply::ok()
+            }
+            RespCommand::UNWATCH => {
+                env.unwatch(client.id(), client.take_watched());
+                reply::ok()
+            }
+            RespCommand::EVAL | RespCommand::EVALSHA => scripting::eval(
+                &self.environment,
+                env,
+                db,
+                &self.data,
+                matches!(self.kind, RespCommand::EVALSHA),
+            ),
+            RespCommand::SCRIPT => scripting::script(env, &self.data),
+            RespCommand::FUNCTION => scripting::function(env, &self.data),
+            RespCommand::PUBLISH => reply::integer(env.publish(&self.data[1], &self.data[2]) as i64),
+            RespCommand::SPUBLISH => {
+                let receivers = env.spublish(&self.data[1], &self.data[2]);
+                // Sharded messages reach the subscribers of replicas, but are
+                // never logged
+                env.also_propagate_to_replicas(db, self.data.clone());
+                reply::integer(receivers as i64)
+            }
+            RespCommand::PUBSUB => pubsub(env, &self.data),
+            RespCommand::CLIENT => client_command(env, client, &self.data),
+            RespCommand::CONFIG => config(env, client, &self.data),
+            RespCommand::FCALL | RespCommand::FCALLRO => scripting::fcall(
+                &self.environment,
+                env,
+                db,
+                &self.data,
+                matches!(self.kind, RespCommand::FCALLRO),
+            ),
+            RespCommand::HSCAN | RespCommand::SSCAN | RespCommand::ZSCAN => {
+                if self.data[2].parse::<u64>().is_err() {
+                    return Ok(reply::error("ERR invalid cursor"));
+                }
+                if let Err(e) = ScanOptions::parse(&self.data[3..], false) {
+                    return Ok(e);
+                }
+
+                // Hashes, sets and sorted sets are not implemented yet, so any
+                // existing key holds a value of the wrong type
+                match env.key_type(db, &self.data[1]) {
+                    Some(_) => reply::wrong_type(),
+                    None => reply::array(vec![reply::bulk("0"), reply::array(Vec::new())]),
+                }
+            }
+            _ => reply::error("ERR unknown command"),
+        };
+
+        Ok(response)
+    }
+
+    pub fn set_kind(&mut self, kind: RespCommand) {
+        self.kind = kind;
     }
 
     pub fn set_data(&mut self, data: Vec<String>) {
@@ -286,9 +1136,16 @@ impl Resp2 {
     }
 
     fn handle_deserialization(&mut self, input: &str) -> Result<(), String> {
-        let mut parts = input.split("\r\n");
+        // Arguments are taken by their declared length, as they may contain CRLF
+        let mut pos = 0;
+        let next_line = |pos: &mut usize| -> Option<&str> {
+            let end = *pos + input.get(*pos..)?.find("\r\n")?;
+            let line = &input[*pos..end];
+            *pos = end + 2;
+            Some(line)
+        };
 
-        let len_str = parts.next().ok_or("Missing RESP2 array header")?;
+        let len_str = next_line(&mut pos).ok_or("Missing RESP2 array header")?;
         if !len_str.starts_with('*') {
             return Err(format!("Invalid RESP2 array header: '{}'", len_str));
         }
@@ -298,10 +1155,10 @@ impl Resp2 {
             .map_err(|_| format!("Invalid RESP2 array length: '{}'", len_str))?;
 
         self.data.clear();
-        self.data.reserve(expected_len);
+        self.data.reserve(expected_len.min(1024));
 
         for _ in 0..expected_len {
-            let size_str = parts.next().ok_or("Missing $<size> header")?;
+            let size_str = next_line(&mut pos).ok_or("Missing $<size> header")?;
             if !size_str.starts_with('$') {
                 return Err(format!("Expected $, got '{}'", size_str));
             }
@@ -310,13 +1167,15 @@ impl Resp2 {
                 .parse::<usize>()
                 .map_err(|_| format!("Invalid bulk string size: '{}'", size_str))?;
 
-            let value = parts.next().ok_or("Missing bulk string content")?;
-            if value.len() != size {
-                return Err(format!(
-                    "Data length mismatch: expected {}, got '{}'",
-                    size, value
-                ));
+            let value = pos
+                .checked_add(size)
+                .and_then(|end| input.get(pos..end))
+                .ok_or("Missing bulk string content")?;
+            pos += size;
+            if input.get(pos..pos + 2) != Some("\r\n") {
+                return Err(format!("Data length mismatch: expected {}, got '{}'", size, value));
             }
+            pos += 2;
 
             self.data.push(value.to_string());
         }
@@ -330,50 +1189,21 @@ impl Resp2 {
         Ok(())
     }
 
-    fn read_master(&self, stream: &mut TcpStream) -> Result<bool, String> {
-        let mut buffer = vec![0; 1024];
-        let n = stream
-            .read(&mut buffer)
-            .map_err(|e| format!("Failed to read from stream: {}", e))?;
-        if n == 0 {
-            return Err("Connection closed by master".to_string());
-        }
-
-        let buffer = match String::from_utf8(buffer[..n].to_vec()) {
-            Ok(s) => s,
-            Err(e) => return Err(format!("Failed to convert bytes to string: {}", e)),
-        };
-        if !buffer.starts_with("+OK") && !buffer.starts_with("+PONG") {
-            return Err(format!("Unexpected response from master: '{}'", buffer));
-        }
-
-        Ok(true)
-    }
-
-    fn propagate(&self) -> Result<(), String> {
-        let env = self.environment.lock().map_err(|e| e.to_string())?;
-        let slaves = env.slaves();
-
-        if slaves.is_empty() {
+    /// Writes a reply to the client. Commands applied from our master's
+    /// replication stream are executed silently.
+    fn respond(&self, client: &Client, reply: &[u8]) -> Result<(), String> {
+        if client.is_master() {
             return Ok(());
         }
+        Self::send(client, reply)
+    }
 
-        if self.literal.is_empty() {
-            return Err("No literal data to propagate".to_string());
-        }
-
-        for slave in slaves {
-            let stream_arc = slave.stream();
-            let mut stream = stream_arc.lock().map_err(|e| e.to_string())?;
-            if stream.write_all(&self.literal).is_err() {
-                return Err("Failed to write to slave stream".to_string());
-            }
-            if stream.flush().is_err() {
-                return Err("Failed to flush slave stream".to_string());
-            }
+    /// Queues `data` for the client, even a master whose link is otherwise silent.
+    fn send(client: &Client, data: &[u8]) -> Result<(), String> {
+        match client.outbox().map(|outbox| outbox.push(data)) {
+            Some(None) => Err("Connection closed".to_string()),
+            _ => Ok(()),
         }
-
-        Ok(())
     }
 }
 
@@ -420,15 +1250,379 @@ impl Serialize<Vec<u8>> for Resp2 {
 
 impl Deserialize<&str> for Resp2 {
     fn deserialize(&mut self, input: &str) -> Result<(), String> {
-        self.set_literal(input.as_bytes().to_vec());
         self.handle_deserialization(input)
     }
 }
 
 impl Deserialize<Vec<u8>> for Resp2 {
     fn deserialize(&mut self, input: Vec<u8>) -> Result<(), String> {
-        self.set_literal(input.clone());
         let input = String::from_utf8(input).map_err(|e| e.to_string())?;
         self.handle_deserialization(&input)
     }
 }
+
+/// Parses a database index given to SELECT or MOVE, or the error reply to send.
+fn parse_db_index(env: &Environment, value: &str) -> Result<usize, Vec<u8>> {
+    match value.parse::<i64>() {
+        Ok(index) if index >= 0 && (index as usize) < env.databases().len() => Ok(index as usize),
+        Ok(_) => Err(reply::error("ERR DB index is out of range")),
+        Err(_) => Err(reply::not_an_integer()),
+    }
+}
+
+/// The MATCH, COUNT and TYPE options shared by the SCAN family.
+struct ScanOptions {
+    pattern: Option<String>,
+    count: usize,
+    kind: Option<String>,
+}
+
+impl ScanOptions {
+    /// Parses the options following the cursor. Only SCAN itself accepts TYPE.
+    fn parse(args: &[String], allow_type: bool) -> Result<Self, Vec<u8>> {
+        let mut options = ScanOptions {
+            pattern: None,
+            count: 10,
+            kind: None,
+        };
+
+        for pair in args.chunks(2) {
+            let [option, value] = pair else {
+                return Err(reply::syntax_error());
+            };
+            match option.to_uppercase().as_str() {
+                "MATCH" => options.pattern = Some(value.clone()),
+                "COUNT" => {
+                    options.count = match value.parse::<i64>() {
+                        Ok(count) if count >= 1 => count as usize,
+                        Ok(_) => return Err(reply::syntax_error()),
+                        Err(_) => return Err(reply::not_an_integer()),
+                    }
+                }
+                "TYPE" if allow_type => {
+                    let kind = value.to_lowercase();
+                    let known = ["string", "list", "set", "zset", "hash", "stream"];
+                    if !known.contains(&kind.as_str()) {
+                        let message = format!("ERR unknown type name '{}'", value);
+                        return Err(reply::error(&message));
+                    }
+                    options.kind = Some(kind);
+                }
+                _ => return Err(reply::syntax_error()),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| {
+            pattern == "*" || glob_match(pattern.as_bytes(), key.as_bytes(), false)
+        })
+    }
+}
+
+/// Runs a PUBSUB subcommand.
+fn pubsub(env: &Environment, args: &[String]) -> Vec<u8> {
+    let subcommand = args[1].to_uppercase();
+    match subcommand.as_str() {
+        "CHANNELS" if args.len() <= 3 => {
+            let pattern = args.get(2).map(|pattern| pattern.as_str());
+            let channels = env.pubsub().active(Subscription::Channel, pattern);
+            reply::array(channels.into_iter().map(|c| reply::bulk(c)).collect())
+        }
+        "SHARDCHANNELS" if args.len() <= 3 => {
+            let pattern = args.get(2).map(|pattern| pattern.as_str());
+            let channels = env.pubsub().active(Subscription::Shard, pattern);
+            reply::array(channels.into_iter().map(|c| reply::bulk(c)).collect())
+        }
+        "NUMSUB" | "SHARDNUMSUB" => {
+            let kind = match subcommand.as_str() {
+                "NUMSUB" => Subscription::Channel,
+                _ => Subscription::Shard,
+            };
+            let mut counts = Vec::new();
+            for channel in &args[2..] {
+                let count = env.pubsub().subscriber_count(kind, channel);
+                counts.push(reply::bulk(channel));
+                counts.push(reply::integer(count as i64));
+            }
+            reply::array(counts)
+        }
+        "NUMPAT" if args.len() == 2 => {
+            reply::integer(env.pubsub().active(Subscription::Pattern, None).len() as i64)
+        }
+        "HELP" if args.len() == 2 => {
+            let lines = [
+                "PUBSUB <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "CHANNELS [<pattern>]",
+                "    Return the currently active channels matching a <pattern> (default: '*').",
+                "NUMPAT",
+                "    Return number of subscriptions to patterns.",
+                "NUMSUB [<channel> ...]",
+                "    Return the number of subscribers for the specified channels, excluding",
+                "    pattern subscriptions(default: no channels).",
+                "SHARDCHANNELS [<pattern>]",
+                "    Return the currently active shard level channels matching a <pattern> (default: '*').",
+                "SHARDNUMSUB [<shardchannel> ...]",
+                "    Return the number of subscribers for the specified shard level channel(s)",
+                "HELP",
+                "    Print this help.",
+            ];
+            reply::array(lines.iter().map(|line| reply::simple(line)).collect())
+        }
+        _ => reply::error(&format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+            args[1]
+        )),
+    }
+}
+
+/// Runs a CONFIG subcommand. Parameters are only set on the command line,
+/// so they can be read but not changed.
+fn config(env: &Environment, client: &Client, args: &[String]) -> Vec<u8> {
+    match args[1].to_uppercase().as_str() {
+        "GET" if args.len() > 2 => {
+            let config = env.config();
+            let mut names: Vec<String> = Vec::new();
+            for pattern in &args[2..] {
+                let pattern = pattern.to_lowercase();
+                // Aliases are only found by their exact name
+                let matched: Vec<String> = match config.get(&pattern) {
+                    Some(_) => vec![pattern],
+                    None => PARAMETERS
+                        .iter()
+                        .filter(|name| glob_match(pattern.as_bytes(), name.as_bytes(), true))
+                        .map(|name| name.to_string())
+                        .collect(),
+                };
+                for name in matched {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+
+            let pairs = names
+                .iter()
+                .map(|name| {
+                    let value = config.get(name).unwrap_or_default();
+                    (name.as_str(), reply::bulk(&value))
+                })
+                .collect();
+            reply::map(pairs, client.protocol() == 3)
+        }
+        _ => reply::error(&format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            args[1]
+        )),
+    }
+}
+
+fn client_command(env: &mut Environment, client: &mut Client, args: &[String]) -> Vec<u8> {
+    let subcommand = args[1].to_uppercase();
+    match subcommand.as_str() {
+        "ID" if args.len() == 2 => reply::integer(client.id() as i64),
+        "GETREDIR" if args.len() == 2 => match env.tracking().options(client.id()) {
+            Some(options) => reply::integer(options.redirect as i64),
+            None => reply::integer(-1),
+        },
+        "TRACKING" if args.len() >= 3 => client_tracking(env, client, args),
+        "CACHING" if args.len() == 3 => {
+            let Some(options) = env
+                .tracking()
+                .options(client.id())
+                .filter(|options| options.optin || options.optout)
+            else {
+                return reply::error(
+                    "ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled",
+                );
+            };
+            match args[2].to_uppercase().as_str() {
+                "YES" if options.optin => client.set_caching(Some(true)),
+                "YES" => return reply::error(
+                    "ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                ),
+                "NO" if options.optout => client.set_caching(Some(false)),
+                "NO" => return reply::error(
+                    "ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.",
+                ),
+                _ => return reply::syntax_error(),
+            }
+            reply::ok()
+        }
+        "TRACKINGINFO" if args.len() == 2 => {
+            let resp3 = client.protocol() == 3;
+            let tracking = env.tracking();
+            let Some(options) = tracking.options(client.id()) else {
+                return reply::map(
+                    vec![
+                        ("flags", reply::array(vec![reply::bulk("off")])),
+                        ("redirect", reply::integer(-1)),
+                        ("prefixes", reply::array(Vec::new())),
+                    ],
+                    resp3,
+                );
+            };
+
+            let mut flags = vec!["on"];
+            if options.bcast {
+                flags.push("bcast");
+            }
+            if options.optin {
+                flags.push("optin");
+                if client.caching() == Some(true) {
+                    flags.push("caching-yes");
+                }
+            }
+            if options.optout {
+                flags.push("optout");
+                if client.caching() == Some(false) {
+                    flags.push("caching-no");
+                }
+            }
+            if options.noloop {
+                flags.push("noloop");
+            }
+            if tracking.has_broken_redirect(client.id()) {
+                flags.push("broken_redirect");
+            }
+            reply::map(
+                vec![
+                    (
+                        "flags",
+                        reply::array(flags.into_iter().map(reply::bulk).collect()),
+                    ),
+                    ("redirect", reply::integer(options.redirect as i64)),
+                    (
+                        "prefixes",
+                        reply::array(options.prefixes.iter().map(|p| reply::bulk(p)).collect()),
+                    ),
+                ],
+                resp3,
+            )
+        }
+        "HELP" if args.len() == 2 => {
+            let lines = [
+                "CLIENT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "CACHING (YES|NO)",
+                "    Enable/disable tracking of the keys for next command in OPTIN/OPTOUT modes.",
+                "GETREDIR",
+                "    Return the client ID we are redirecting to when tracking is enabled.",
+                "ID",
+                "    Return the ID of the current connection.",
+                "TRACKING (ON|OFF) [REDIRECT <id>] [BCAST] [PREFIX <prefix>]",
+                "         [PREFIX <prefix> ...] [OPTIN] [OPTOUT] [NOLOOP]",
+                "    Control server assisted client side caching.",
+                "TRACKINGINFO",
+                "    Report tracking status for the current connection.",
+                "HELP",
+                "    Print this help.",
+            ];
+            reply::array(lines.iter().map(|line| reply::simple(line)).collect())
+        }
+        _ => reply::error(&format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try CLIENT HELP.",
+            args[1]
+        )),
+    }
+}
+
+/// CLIENT TRACKING ON|OFF [REDIRECT id] [BCAST] [PREFIX prefix ...] [OPTIN]
+/// [OPTOUT] [NOLOOP]
+fn client_tracking(env: &mut Environment, client: &mut Client, args: &[String]) -> Vec<u8> {
+    let mut options = TrackingOptions::default();
+    let mut redirecting = false;
+    let mut i = 3;
+    while i < args.len() {
+        let has_value = i + 1 < args.len();
+        match args[i].to_uppercase().as_str() {
+            "REDIRECT" if has_value => {
+                if redirecting {
+                    return reply::error("ERR A client can only redirect to a single other client");
+                }
+                let Ok(id) = args[i + 1].parse::<u64>() else {
+                    return reply::not_an_integer();
+                };
+                if !Client::is_connected(id) {
+                    return reply::error("ERR The client ID you want redirect to does not exist");
+                }
+                redirecting = true;
+                options.redirect = id;
+                i += 1;
+            }
+            "PREFIX" if has_value => {
+                options.prefixes.push(args[i + 1].clone());
+                i += 1;
+            }
+            "BCAST" => options.bcast = true,
+            "OPTIN" => options.optin = true,
+            "OPTOUT" => options.optout = true,
+            "NOLOOP" => options.noloop = true,
+            _ => return reply::syntax_error(),
+        }
+        i += 1;
+    }
+
+    match args[2].to_uppercase().as_str() {
+        "ON" => {
+            if !options.bcast && !options.prefixes.is_empty() {
+                return reply::error("ERR PREFIX option requires BCAST mode to be enabled");
+            }
+            let current = env.tracking().options(client.id()).cloned();
+            if let Some(current) = &current {
+                if current.bcast != options.bcast {
+                    return reply::error("ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.");
+                }
+            }
+            if options.bcast && (options.optin || options.optout) {
+                return reply::error("ERR OPTIN and OPTOUT are not compatible with BCAST");
+            }
+            if options.optin && options.optout {
+                return reply::error("ERR You can't use both OPTIN and OPTOUT");
+            }
+            if let Some(current) = &current {
+                if (current.optin && options.optout) || (current.optout && options.optin) {
+                    return reply::error("ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.");
+                }
+            }
+
+            // Prefixes accumulate over calls, and must never overlap, so a
+            // key matches at most one of them
+            let existing = current.map(|current| current.prefixes).unwrap_or_default();
+            for (index, prefix) in options.prefixes.iter().enumerate() {
+                let overlaps = |other: &String| {
+                    other != prefix
+                        && (other.starts_with(prefix.as_str())
+                            || prefix.starts_with(other.as_str()))
+                };
+                if let Some(other) = existing.iter().find(|other| overlaps(other)) {
+                    return reply::error(&format!("ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.", prefix, other));
+                }
+                if let Some(other) = options.prefixes[index + 1..]
+                    .iter()
+                    .find(|other| overlaps(other))
+                {
+                    return reply::error(&format!("ERR Prefix '{}' overlaps with another provided prefix '{}'. Prefixes for a single client must not overlap.", prefix, other));
+                }
+            }
+            for prefix in existing {
+                if !options.prefixes.contains(&prefix) {
+                    options.prefixes.push(prefix);
+                }
+            }
+            if options.bcast && options.prefixes.is_empty() {
+                options.prefixes.push(String::new());
+            }
+
+            env.tracking_mut().enable(client.id(), options);
+            reply::ok()
+        }
+        "OFF" => {
+            env.tracking_mut().disable(client.id());
+            client.set_caching(None);
+            reply::ok()
+        }
+        _ => reply::syntax_error(),
+    }
+}
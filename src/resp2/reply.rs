@@ -0,0 +1,82 @@
+//! Builders for the RESP2 replies sent back to clients.
+
+pub fn ok() -> Vec<u8> {
+    b"+OK\r\n".to_vec()
+}
+
+pub fn simple(value: &str) -> Vec<u8> {
+    format!("+{}\r\n", value).into_bytes()
+}
+
+/// `message` starts with the error code, e.g. `ERR syntax error`.
+pub fn error(message: &str) -> Vec<u8> {
+    format!("-{}\r\n", message).into_bytes()
+}
+
+pub fn integer(value: i64) -> Vec<u8> {
+    format!(":{}\r\n", value).into_bytes()
+}
+
+pub fn bulk(value: &str) -> Vec<u8> {
+    format!("${}\r\n{}\r\n", value.len(), value).into_bytes()
+}
+
+/// Concatenates already encoded replies into an array.
+pub fn array(items: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", items.len()).into_bytes();
+    for item in items {
+        out.extend(item);
+    }
+    out
+}
+
+/// An out-of-band RESP3 push, such as a Pub/Sub message.
+pub fn push(items: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = format!(">{}\r\n", items.len()).into_bytes();
+    for item in items {
+        out.extend(item);
+    }
+    out
+}
+
+/// A map of `pairs`, flattened into an array for RESP2 clients.
+pub fn map(pairs: Vec<(&str, Vec<u8>)>, resp3: bool) -> Vec<u8> {
+    let mut out = match resp3 {
+        true => format!("%{}\r\n", pairs.len()),
+        false => format!("*{}\r\n", pairs.len() * 2),
+    }
+    .into_bytes();
+    for (key, value) in pairs {
+        out.extend(bulk(key));
+        out.extend(value);
+    }
+    out
+}
+
+pub fn null() -> Vec<u8> {
+    b"$-1\r\n".to_vec()
+}
+
+/// The reply of an EXEC whose transaction was aborted by WATCH.
+pub fn null_array() -> Vec<u8> {
+    b"*-1\r\n".to_vec()
+}
+
+pub fn syntax_error() -> Vec<u8> {
+    error("ERR syntax error")
+}
+
+pub fn wrong_arity(command: &str) -> Vec<u8> {
+    error(&format!(
+        "ERR wrong number of arguments for '{}' command",
+        command.to_lowercase()
+    ))
+}
+
+pub fn not_an_integer() -> Vec<u8> {
+    error("ERR value is not an integer or out of range")
+}
+
+pub fn wrong_type() -> Vec<u8> {
+    error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
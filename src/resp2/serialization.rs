@@ -1,3 +1,12 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The `proto-max-bulk-len` set at startup.
+static MAX_BULK_LEN: AtomicUsize = AtomicUsize::new(512 * 1024 * 1024);
+
+pub fn set_max_bulk_len(len: usize) {
+    MAX_BULK_LEN.store(len, Ordering::Relaxed);
+}
+
 #[allow(dead_code)]
 pub trait Serialize<T> {
     fn serialize_bulk_string(&self) -> T;
@@ -7,3 +16,93 @@ pub trait Serialize<T> {
 pub trait Deserialize<T> {
     fn deserialize(&mut self, input: T) -> Result<(), String>;
 }
+
+/// Splits the first complete RESP array off `buf`, returning its bytes and
+/// length, or `None` while more data is needed.
+pub fn try_parse_one_command(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, String> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    // Reads the CRLF-terminated line starting at `pos`, if it fully arrived
+    let line_at = |pos: usize| -> Option<(&[u8], usize)> {
+        let end = buf[pos..].windows(2).position(|w| w == b"\r\n")?;
+        Some((&buf[pos..pos + end], pos + end + 2))
+    };
+    let as_str = |line: &[u8]| String::from_utf8_lossy(line).into_owned();
+
+    let (first, mut total) = match line_at(0) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if !first.starts_with(b"*") {
+        return Err(format!("Expected RESP array, got '{}'", as_str(first)));
+    }
+
+    let count = std::str::from_utf8(&first[1..])
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .ok_or_else(|| format!("Invalid array count '{}'", as_str(first)))?;
+
+    for _ in 0..count {
+        let (size_line, next) = match line_at(total) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if !size_line.starts_with(b"$") {
+            return Err(format!("Expected bulk string, got '{}'", as_str(size_line)));
+        }
+
+        let len = std::str::from_utf8(&size_line[1..])
+            .ok()
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|len| *len <= MAX_BULK_LEN.load(Ordering::Relaxed))
+            .ok_or_else(|| format!("Invalid bulk length '{}'", as_str(size_line)))?;
+
+        total = next
+            .checked_add(len)
+            .and_then(|end| end.checked_add(2))
+            .ok_or_else(|| format!("Invalid bulk length '{}'", as_str(size_line)))?;
+        if total > buf.len() {
+            return Ok(None);
+        }
+        if &buf[total - 2..total] != b"\r\n" {
+            return Err("Bulk string length mismatch".to_string());
+        }
+    }
+
+    Ok(Some((buf[..total].to_vec(), total)))
+}
+
+/// Encodes a command as a RESP array of bulk strings.
+pub fn encode_command(args: &[String]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::try_parse_one_command;
+
+    #[test]
+    fn waits_for_the_rest_of_a_command() {
+        assert_eq!(try_parse_one_command(b"*1\r\n$4\r\nPI").unwrap(), None);
+        assert_eq!(
+            try_parse_one_command(b"*1\r\n$4\r\nPING\r\n*1").unwrap(),
+            Some((b"*1\r\n$4\r\nPING\r\n".to_vec(), 14))
+        );
+    }
+
+    #[test]
+    fn rejects_bulk_lengths_past_the_limit() {
+        assert!(try_parse_one_command(b"*1\r\n$18446744073709551615\r\n").is_err());
+        assert!(try_parse_one_command(b"*1\r\n$536870913\r\n").is_err());
+        assert_eq!(try_parse_one_command(b"*1\r\n$536870912\r\n").unwrap(), None);
+    }
+}
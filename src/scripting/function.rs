@@ -0,0 +1,521 @@
+//! Redis Functions: libraries of Lua functions loaded with FUNCTION LOAD and
+//! called by name with FCALL. Unlike EVAL scripts, libraries belong to the
+//! dataset, so they are saved in snapshots and replicated.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mlua::{Function, Lua, RegistryKey, Table, Value, Variadic};
+
+use super::{kill, message, new_interpreter, run, split_keys, BUSY_ERROR, LOAD_DEADLINE};
+use crate::{
+    common::{glob_match, Environment},
+    rdb,
+    resp2::reply,
+};
+
+/// How long the body of a library may run while it is loaded.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Flags a function may declare when it is registered.
+const FLAGS: [&str; 5] = [
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+struct Library {
+    code: String,
+    functions: BTreeMap<String, Registered>,
+}
+
+struct Registered {
+    callback: RegistryKey,
+    description: Option<String>,
+    flags: Vec<String>,
+}
+
+impl Registered {
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+/// The loaded libraries, with an interpreter of their own.
+pub struct Functions {
+    lua: Lua,
+    libraries: BTreeMap<String, Library>,
+    /// Library defining each function.
+    owners: HashMap<String, String>,
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Functions::new()
+    }
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Functions {
+            lua: new_interpreter(),
+            libraries: BTreeMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Source code of every library, as saved in snapshots.
+    pub fn codes(&self) -> Vec<String> {
+        self.libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    pub fn flush(&mut self) {
+        *self = Functions::new();
+    }
+
+    /// Loads the library defined by `code`, replacing a library of the same
+    /// name only when `replace` is set. Returns the name of the library.
+    pub fn load(&mut self, code: &str, replace: bool) -> Result<String, String> {
+        let (name, body) = parse_metadata(code)?;
+        if !replace && self.libraries.contains_key(&name) {
+            return Err(format!("ERR Library '{}' already exists", name));
+        }
+
+        let functions = self.evaluate(body)?;
+        if functions.is_empty() {
+            return Err("ERR No functions registered".to_string());
+        }
+        if let Some(taken) = functions.keys().find(|function| {
+            self.owners
+                .get(*function)
+                .is_some_and(|owner| *owner != name)
+        }) {
+            return Err(format!("ERR Function {} already exists", taken));
+        }
+
+        self.delete(&name);
+        for function in functions.keys() {
+            self.owners.insert(function.clone(), name.clone());
+        }
+        self.libraries.insert(
+            name.clone(),
+            Library {
+                code: code.to_string(),
+                functions,
+            },
+        );
+        Ok(name)
+    }
+
+    /// Runs the body of a library, collecting the functions it registers.
+    fn evaluate(&self, body: &str) -> Result<BTreeMap<String, Registered>, String> {
+        let chunk = self
+            .lua
+            .load(body)
+            .set_name("@user_function")
+            .into_function()
+            .map_err(|e| format!("ERR Error compiling function: {}", message(&e)))?;
+
+        let registered = RefCell::new(BTreeMap::new());
+        if let Ok(mut deadline) = LOAD_DEADLINE.lock() {
+            *deadline = Some(Instant::now() + LOAD_TIMEOUT);
+        }
+        let outcome = self.lua.scope(|scope| {
+            let redis: Table = self.lua.globals().get("redis")?;
+            redis.set(
+                "register_function",
+                scope.create_function(|lua, args| register(lua, &registered, args))?,
+            )?;
+            chunk.call::<_, ()>(())
+        });
+
+        if let Ok(mut deadline) = LOAD_DEADLINE.lock() {
+            *deadline = None;
+        }
+        if let Ok(redis) = self.lua.globals().get::<_, Table>("redis") {
+            let _ = redis.set("register_function", Value::Nil);
+        }
+        outcome.map_err(|e| format!("ERR Error registering functions: {}", message(&e)))?;
+        Ok(registered.into_inner())
+    }
+
+    fn delete(&mut self, name: &str) -> bool {
+        let Some(library) = self.libraries.remove(name) else {
+            return false;
+        };
+        for function in library.functions.keys() {
+            self.owners.remove(function);
+        }
+        true
+    }
+
+    /// Builds the libraries anew from `codes`, each loaded with its replace
+    /// flag, so a failure leaves the current libraries untouched.
+    fn rebuild(codes: Vec<(String, bool)>) -> Result<Functions, String> {
+        let mut functions = Functions::new();
+        for (code, replace) in codes {
+            functions.load(&code, replace)?;
+        }
+        Ok(functions)
+    }
+
+    /// Calls function `name` with FCALL or, with `read_only`, FCALL_RO.
+    #[allow(clippy::too_many_arguments)]
+    fn call(
+        &self,
+        environment: &Arc<Mutex<Environment>>,
+        env: &mut Environment,
+        db: usize,
+        name: &str,
+        keys: &[String],
+        argv: &[String],
+        read_only: bool,
+    ) -> Vec<u8> {
+        let Some(function) = self
+            .owners
+            .get(name)
+            .and_then(|owner| self.libraries.get(owner))
+            .and_then(|library| library.functions.get(name))
+        else {
+            return reply::error("ERR Function not found");
+        };
+
+        let no_writes = function.has_flag("no-writes");
+        if !no_writes {
+            if read_only {
+                return reply::error(
+                    "ERR Can not execute a script with write flag using *_ro command.",
+                );
+            }
+            if env.role() == "slave" && env.config().replica_read_only {
+                return reply::error("READONLY You can't write against a read only replica.");
+            }
+            let maxmemory = env.config().maxmemory;
+            if !function.has_flag("allow-oom") && maxmemory > 0 && env.used_memory() > maxmemory {
+                return reply::error("OOM command not allowed when used memory > 'maxmemory'.");
+            }
+        }
+
+        let callback = match self.lua.registry_value::<Function>(&function.callback) {
+            Ok(callback) => callback,
+            Err(e) => return reply::error(&format!("ERR {}", message(&e))),
+        };
+        let args = (keys.to_vec(), argv.to_vec());
+        run(
+            &self.lua,
+            environment,
+            env,
+            db,
+            callback,
+            args,
+            no_writes,
+            name,
+        )
+    }
+}
+
+/// Reads the `#!lua name=<library>` line a library starts with, returning the
+/// library name and the code following it.
+fn parse_metadata(code: &str) -> Result<(String, &str), String> {
+    let (shebang, body) = code.split_once('\n').unwrap_or((code, ""));
+    let Some(shebang) = shebang.strip_prefix("#!") else {
+        return Err("ERR Missing library metadata".to_string());
+    };
+
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    let name = name.ok_or("ERR Library name was not given")?;
+    if !valid_name(&name) {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok((name, body))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Implements `redis.register_function`, called either with a name and a
+/// callback or with a table of named arguments that may also carry flags and
+/// a description.
+fn register<'lua>(
+    lua: &'lua Lua,
+    registered: &RefCell<BTreeMap<String, Registered>>,
+    args: Variadic<Value<'lua>>,
+) -> mlua::Result<()> {
+    let fail = |message: &str| Err(mlua::Error::RuntimeError(message.to_string()));
+
+    let (name, callback, flags, description) = match args.as_slice() {
+        [Value::String(name), callback] => (
+            name.to_str()?.to_string(),
+            callback.clone(),
+            Vec::new(),
+            None,
+        ),
+        [Value::Table(table)] => {
+            let (mut name, mut callback, mut flags, mut description) =
+                (None, Value::Nil, Vec::new(), None);
+            for pair in table.clone().pairs::<String, Value>() {
+                let (key, value) = pair?;
+                match (key.as_str(), value) {
+                    ("function_name", Value::String(s)) => name = Some(s.to_str()?.to_string()),
+                    ("callback", value) => callback = value,
+                    ("description", Value::String(s)) => {
+                        description = Some(s.to_str()?.to_string())
+                    }
+                    ("flags", Value::Table(list)) => {
+                        for flag in list.sequence_values::<String>() {
+                            flags.push(flag?);
+                        }
+                    }
+                    _ => return fail("unknown argument given to redis.register_function"),
+                }
+            }
+            let Some(name) = name else {
+                return fail("redis.register_function must get a function name argument");
+            };
+            (name, callback, flags, description)
+        }
+        _ => return fail("wrong number of arguments to redis.register_function"),
+    };
+
+    if !valid_name(&name) {
+        return fail("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long");
+    }
+    let Value::Function(callback) = callback else {
+        return fail("callback argument given to redis.register_function must be a function");
+    };
+    if let Some(flag) = flags.iter().find(|flag| !FLAGS.contains(&flag.as_str())) {
+        return fail(&format!("unknown flag given: {}", flag));
+    }
+
+    let mut registered = registered.borrow_mut();
+    if registered.contains_key(&name) {
+        return fail("Function already exists in the library");
+    }
+    registered.insert(
+        name,
+        Registered {
+            callback: lua.create_registry_value(callback)?,
+            description,
+            flags,
+        },
+    );
+    Ok(())
+}
+
+/// Runs FCALL or, with `read_only`, FCALL_RO. `args` holds the command name,
+/// the function name, the number of keys, the keys and the arguments.
+pub fn fcall(
+    environment: &Arc<Mutex<Environment>>,
+    env: &mut Environment,
+    db: usize,
+    args: &[String],
+    read_only: bool,
+) -> Vec<u8> {
+    let (keys, argv) = match split_keys(args) {
+        Ok(split) => split,
+        Err(e) => return e,
+    };
+
+    let Some(scripting) = env.take_scripting() else {
+        return reply::error(BUSY_ERROR);
+    };
+    let response =
+        scripting
+            .functions()
+            .call(environment, env, db, &args[1], keys, argv, read_only);
+    env.set_scripting(scripting);
+    response
+}
+
+/// Runs a FUNCTION subcommand. Those changing the libraries count as a change
+/// to the dataset, which replicates them.
+pub fn function(env: &mut Environment, args: &[String]) -> Vec<u8> {
+    let subcommand = args[1].to_uppercase();
+    if subcommand == "KILL" && args.len() == 2 {
+        return kill();
+    }
+
+    let Some(scripting) = env.scripting_mut() else {
+        return reply::error(BUSY_ERROR);
+    };
+    let functions = scripting.functions_mut();
+
+    let outcome = match subcommand.as_str() {
+        "LOAD" if args.len() == 3 => functions
+            .load(&args[2], false)
+            .map(|name| reply::bulk(&name)),
+        "LOAD" if args.len() == 4 && args[2].eq_ignore_ascii_case("REPLACE") => functions
+            .load(&args[3], true)
+            .map(|name| reply::bulk(&name)),
+        "LOAD" if args.len() == 4 => Err(format!("ERR Unknown option given: {}", args[2])),
+        "DELETE" if args.len() == 3 => match functions.delete(&args[2]) {
+            true => Ok(reply::ok()),
+            false => Err("ERR Library not found".to_string()),
+        },
+        "FLUSH" if args.len() <= 3 => {
+            if args.len() == 3 && !["ASYNC", "SYNC"].contains(&args[2].to_uppercase().as_str()) {
+                return reply::error("ERR FUNCTION FLUSH only supports SYNC|ASYNC option");
+            }
+            functions.flush();
+            Ok(reply::ok())
+        }
+        "LIST" => return list(functions, &args[2..]),
+        "DUMP" if args.len() == 2 => {
+            return reply::bulk(&STANDARD.encode(rdb::dump_functions(&functions.codes())))
+        }
+        "RESTORE" if args.len() == 3 || args.len() == 4 => restore(functions, &args[2..]),
+        "HELP" if args.len() == 2 => return help(),
+        _ => {
+            return reply::error(&format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try FUNCTION HELP.",
+                args[1]
+            ))
+        }
+    };
+
+    match outcome {
+        Ok(response) => {
+            env.mark_dirty();
+            response
+        }
+        Err(e) => reply::error(&e),
+    }
+}
+
+/// FUNCTION RESTORE <payload> [FLUSH|APPEND|REPLACE]. The payload is the one
+/// FUNCTION DUMP returns: Redis' dump format, base64 encoded as command
+/// arguments must be valid UTF-8 here.
+fn restore(functions: &mut Functions, args: &[String]) -> Result<Vec<u8>, String> {
+    let codes = STANDARD
+        .decode(&args[0])
+        .ok()
+        .and_then(|payload| rdb::load_functions(&payload).ok())
+        .ok_or("ERR payload version or checksum are wrong")?;
+
+    let policy = args
+        .get(1)
+        .map_or("APPEND".to_string(), |p| p.to_uppercase());
+    let mut all: Vec<(String, bool)> =
+        match policy.as_str() {
+            "FLUSH" => Vec::new(),
+            "APPEND" | "REPLACE" => functions
+                .codes()
+                .into_iter()
+                .map(|code| (code, false))
+                .collect(),
+            _ => return Err(
+                "ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE."
+                    .to_string(),
+            ),
+        };
+    all.extend(codes.into_iter().map(|code| (code, policy == "REPLACE")));
+
+    *functions = Functions::rebuild(all)?;
+    Ok(reply::ok())
+}
+
+/// FUNCTION LIST [WITHCODE] [LIBRARYNAME <pattern>].
+fn list(functions: &Functions, args: &[String]) -> Vec<u8> {
+    let (mut with_code, mut pattern) = (false, None);
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "WITHCODE" if !with_code => with_code = true,
+            "LIBRARYNAME" if pattern.is_none() && i + 1 < args.len() => {
+                i += 1;
+                pattern = Some(args[i].as_str());
+            }
+            _ => return reply::error(&format!("ERR Unknown argument {}", args[i])),
+        }
+        i += 1;
+    }
+
+    let libraries = functions
+        .libraries
+        .iter()
+        .filter(|(name, _)| {
+            pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), name.as_bytes(), false))
+        })
+        .map(|(name, library)| {
+            let entries = library
+                .functions
+                .iter()
+                .map(|(function, registered)| {
+                    reply::array(vec![
+                        reply::bulk("name"),
+                        reply::bulk(function),
+                        reply::bulk("description"),
+                        registered
+                            .description
+                            .as_deref()
+                            .map_or_else(reply::null, reply::bulk),
+                        reply::bulk("flags"),
+                        reply::array(registered.flags.iter().map(|f| reply::simple(f)).collect()),
+                    ])
+                })
+                .collect();
+
+            let mut fields = vec![
+                reply::bulk("library_name"),
+                reply::bulk(name),
+                reply::bulk("engine"),
+                reply::bulk("LUA"),
+                reply::bulk("functions"),
+                reply::array(entries),
+            ];
+            if with_code {
+                fields.push(reply::bulk("library_code"));
+                fields.push(reply::bulk(&library.code));
+            }
+            reply::array(fields)
+        })
+        .collect();
+
+    reply::array(libraries)
+}
+
+fn help() -> Vec<u8> {
+    let lines = [
+        "FUNCTION <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+        "LOAD [REPLACE] <FUNCTION CODE>",
+        "    Create a new library with the given library name and code.",
+        "DELETE <LIBRARY NAME>",
+        "    Delete the given library.",
+        "LIST [LIBRARYNAME PATTERN] [WITHCODE]",
+        "    Return general information on all the libraries.",
+        "KILL",
+        "    Kill the current running function.",
+        "FLUSH [ASYNC|SYNC]",
+        "    Delete all the libraries.",
+        "DUMP",
+        "    Return a serialized payload representing the current libraries.",
+        "RESTORE <PAYLOAD> [FLUSH|APPEND|REPLACE]",
+        "    Restore the libraries represented by the given payload.",
+        "HELP",
+        "    Print this help.",
+    ];
+    reply::array(lines.iter().map(|line| reply::simple(line)).collect())
+}
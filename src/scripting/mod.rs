@@ -0,0 +1,604 @@
+//! Lua scripting: EVAL and EVALSHA run Lua 5.1 scripts that call back into
+//! the command dispatcher through `redis.call` and `redis.pcall`. A script
+//! keeps the environment locked for its whole run, which makes it atomic, and
+//! its writes are replicated as the commands it called rather than as itself.
+
+mod function;
+
+pub use function::*;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mlua::{
+    Function, HookTriggers, IntoLuaMulti, Lua, LuaOptions, RegistryKey, StdLib, Table, Value,
+    Variadic,
+};
+
+use crate::{
+    common::{log, Client, Environment, LogLevel},
+    resp2::{command::RespCommand, reply, Resp2},
+};
+
+/// The levels of `redis.log`, in the order of their `redis.LOG_*` values.
+const LOG_LEVELS: [(&str, LogLevel); 4] = [
+    ("LOG_DEBUG", LogLevel::Debug),
+    ("LOG_VERBOSE", LogLevel::Verbose),
+    ("LOG_NOTICE", LogLevel::Notice),
+    ("LOG_WARNING", LogLevel::Warning),
+];
+
+/// Instructions executed between two checks for SCRIPT KILL.
+const KILL_CHECK_INTERVAL: u32 = 100_000;
+
+pub const BUSY_ERROR: &str =
+    "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.";
+
+/// The Lua interpreter running EVAL scripts and the scripts compiled into
+/// it, by SHA1 digest, next to the function libraries and their own interpreter.
+pub struct Scripting {
+    lua: Lua,
+    scripts: HashMap<String, RegistryKey>,
+    functions: Functions,
+}
+
+/// What `redis.call` works with while a script runs.
+struct Context<'a> {
+    env: &'a mut Environment,
+    /// Stands in for the caller, so a SELECT in the script does not leak to it.
+    client: Client,
+    /// Whether the script declared it never writes.
+    read_only: bool,
+}
+
+/// The script being run. It is kept apart from the environment, which stays
+/// locked until the script ends, so other clients can still find out that
+/// the server is busy and ask for the script to stop.
+struct Running {
+    started: Instant,
+    /// Run time after which other clients are answered with BUSY.
+    threshold: Duration,
+    wrote: bool,
+    killed: bool,
+}
+
+static RUNNING: Mutex<Option<Running>> = Mutex::new(None);
+
+/// When the function library being loaded runs out of time. Loading is not a
+/// script run, so it cannot be killed and is cut short instead.
+static LOAD_DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// An error reply raised from Lua, returned to the caller unchanged.
+#[derive(Debug)]
+struct ReplyError(String);
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ReplyError {}
+
+impl Default for Scripting {
+    fn default() -> Self {
+        Scripting::new()
+    }
+}
+
+impl Scripting {
+    pub fn new() -> Self {
+        Scripting {
+            lua: new_interpreter(),
+            scripts: HashMap::new(),
+            functions: Functions::new(),
+        }
+    }
+
+    pub fn functions(&self) -> &Functions {
+        &self.functions
+    }
+
+    pub fn functions_mut(&mut self) -> &mut Functions {
+        &mut self.functions
+    }
+
+    /// Forgets every EVAL script, leaving function libraries alone.
+    fn flush_scripts(&mut self) {
+        self.lua = new_interpreter();
+        self.scripts.clear();
+    }
+
+    /// Compiles `body` unless it already was, returning its digest.
+    fn load(&mut self, body: &str) -> Result<String, Vec<u8>> {
+        let sha = sha1_hex(body.as_bytes());
+        if self.scripts.contains_key(&sha) {
+            return Ok(sha);
+        }
+
+        let key = self
+            .lua
+            .load(body)
+            .set_name("@user_script")
+            .into_function()
+            .and_then(|function| self.lua.create_registry_value(function))
+            .map_err(|e| {
+                reply::error(&format!(
+                    "ERR Error compiling script (new function): {}",
+                    message(&e)
+                ))
+            })?;
+        self.scripts.insert(sha.clone(), key);
+        Ok(sha)
+    }
+}
+
+fn new_interpreter() -> Lua {
+    Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )
+    .and_then(|lua| prepare(&lua).map(|()| lua))
+    .expect("Failed to initialize the Lua interpreter")
+}
+
+/// Calls `function` with `args` on behalf of a client in database `db`,
+/// returning the reply to send. `name` identifies the script in errors.
+#[allow(clippy::too_many_arguments)]
+fn run<'lua>(
+    lua: &'lua Lua,
+    environment: &Arc<Mutex<Environment>>,
+    env: &mut Environment,
+    db: usize,
+    function: Function<'lua>,
+    args: impl IntoLuaMulti<'lua>,
+    read_only: bool,
+    name: &str,
+) -> Vec<u8> {
+    let threshold = Duration::from_millis(env.config().busy_reply_threshold);
+    if let Ok(mut running) = RUNNING.lock() {
+        *running = Some(Running {
+            started: Instant::now(),
+            threshold,
+            wrote: false,
+            killed: false,
+        });
+    }
+
+    let mut client = Client::new();
+    client.select(db);
+    let context = RefCell::new(Context {
+        env,
+        client,
+        read_only,
+    });
+
+    let outcome = lua.scope(|scope| {
+        let redis: Table = lua.globals().get("redis")?;
+        redis.set(
+            "call",
+            scope.create_function(|lua, args| call(lua, environment, &context, args, true))?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(|lua, args| call(lua, environment, &context, args, false))?,
+        )?;
+
+        let value: Value = function.call(args)?;
+        to_reply(&value)
+    });
+
+    // Nothing can be called outside a run, e.g. while a library loads
+    if let Ok(redis) = lua.globals().get::<_, Table>("redis") {
+        let _ = redis.set("call", Value::Nil);
+        let _ = redis.set("pcall", Value::Nil);
+    }
+    if let Ok(mut running) = RUNNING.lock() {
+        *running = None;
+    }
+
+    outcome.unwrap_or_else(|e| match reply_error(&e) {
+        Some(ReplyError(message)) => reply::error(message),
+        None => reply::error(&format!(
+            "ERR Error running script (call to {}): {}",
+            name,
+            message(&e)
+        )),
+    })
+}
+
+/// Sets up the `redis` library and the hook that lets SCRIPT KILL stop a script.
+fn prepare(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    // Scripts have no business with the filesystem
+    globals.set("dofile", Value::Nil)?;
+    globals.set("loadfile", Value::Nil)?;
+
+    let redis = lua.create_table()?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, data: mlua::String| Ok(sha1_hex(data.as_bytes())))?,
+    )?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, message: String| lua.create_table_from([("err", message)]))?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, message: String| lua.create_table_from([("ok", message)]))?,
+    )?;
+    redis.set(
+        "log",
+        lua.create_function(|_, (level, message): (i64, Variadic<mlua::String>)| {
+            if message.is_empty() {
+                return Err(mlua::Error::RuntimeError(
+                    "redis.log() requires two arguments or more.".to_string(),
+                ));
+            }
+            let Some((_, level)) = usize::try_from(level).ok().and_then(|i| LOG_LEVELS.get(i))
+            else {
+                return Err(mlua::Error::RuntimeError(
+                    "Invalid debug level.".to_string(),
+                ));
+            };
+            let parts: Vec<_> = message.iter().map(|part| part.to_string_lossy()).collect();
+            log(*level, &parts.join(" "));
+            Ok(())
+        })?,
+    )?;
+    for (index, (name, _)) in LOG_LEVELS.iter().enumerate() {
+        redis.set(*name, index)?;
+    }
+    globals.set("redis", redis)?;
+
+    // Every script shares the interpreter, so none may leave globals behind
+    // for the next one to trip over
+    let protection = lua.create_table()?;
+    protection.set(
+        "__newindex",
+        lua.create_function(|_, (_, name): (Table, mlua::String)| -> mlua::Result<()> {
+            Err(mlua::Error::RuntimeError(format!(
+                "Script attempted to create global variable '{}'",
+                name.to_string_lossy()
+            )))
+        })?,
+    )?;
+    globals.set_metatable(Some(protection));
+
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL),
+        |_, _| {
+            let killed = RUNNING
+                .lock()
+                .is_ok_and(|running| running.as_ref().is_some_and(|r| r.killed));
+            if killed {
+                return Err(mlua::Error::external(ReplyError(
+                    "ERR Script killed by user with SCRIPT KILL...".to_string(),
+                )));
+            }
+            let expired = LOAD_DEADLINE
+                .lock()
+                .is_ok_and(|deadline| deadline.is_some_and(|at| Instant::now() >= at));
+            if expired {
+                return Err(mlua::Error::RuntimeError(
+                    "FUNCTION LOAD timeout".to_string(),
+                ));
+            }
+            Ok(())
+        },
+    );
+    Ok(())
+}
+
+/// Implements `redis.call` and, when not `raise`, `redis.pcall`, which returns
+/// error replies as a table instead of raising them.
+fn call<'lua>(
+    lua: &'lua Lua,
+    environment: &Arc<Mutex<Environment>>,
+    context: &RefCell<Context>,
+    args: Variadic<Value<'lua>>,
+    raise: bool,
+) -> mlua::Result<Value<'lua>> {
+    let failure = |message: &str| -> mlua::Result<Value<'lua>> {
+        if raise {
+            Err(mlua::Error::external(ReplyError(message.to_string())))
+        } else {
+            Ok(Value::Table(lua.create_table_from([("err", message)])?))
+        }
+    };
+
+    if args.is_empty() {
+        return failure("ERR Please specify at least one argument for this redis lib call");
+    }
+    let mut data = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        match arg {
+            Value::String(s) => data.push(s.to_string_lossy().into_owned()),
+            Value::Integer(n) => data.push(n.to_string()),
+            Value::Number(n) => data.push(n.to_string()),
+            _ => return failure("ERR Lua redis lib command arguments must be strings or integers"),
+        }
+    }
+
+    let mut command = Resp2::new(environment.clone());
+    command.set_kind(RespCommand::from_str(&data[0]));
+    command.set_data(data);
+
+    let response = {
+        let context = &mut *context.borrow_mut();
+        let dirty = context.env.dirty();
+        let response = command
+            .call(context.env, &mut context.client, context.read_only)
+            .map_err(mlua::Error::RuntimeError)?;
+        if context.env.dirty() > dirty {
+            if let Ok(Some(running)) = RUNNING.lock().as_deref_mut() {
+                running.wrote = true;
+            }
+        }
+        response
+    };
+
+    if response.starts_with(b"-") {
+        let message = String::from_utf8_lossy(&response[1..]);
+        return failure(message.trim_end());
+    }
+    parse_reply(lua, &response, &mut 0)
+}
+
+/// Converts a RESP2 reply to the Lua value a script receives.
+fn parse_reply<'lua>(lua: &'lua Lua, reply: &[u8], pos: &mut usize) -> mlua::Result<Value<'lua>> {
+    let start = *pos;
+    let end = reply[start..]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|i| start + i)
+        .ok_or_else(|| mlua::Error::RuntimeError("Truncated reply".to_string()))?;
+    let line = String::from_utf8_lossy(&reply[start + 1..end]);
+    *pos = end + 2;
+
+    let length = || {
+        line.parse::<i64>()
+            .map_err(|_| mlua::Error::RuntimeError(format!("Invalid reply length '{}'", line)))
+    };
+
+    Ok(match reply[start] {
+        b'+' => Value::Table(lua.create_table_from([("ok", line.as_ref())])?),
+        b'-' => Value::Table(lua.create_table_from([("err", line.as_ref())])?),
+        b':' => Value::Integer(length()? as mlua::Integer),
+        b'$' => match usize::try_from(length()?) {
+            Ok(len) => {
+                let value = lua.create_string(&reply[*pos..*pos + len])?;
+                *pos += len + 2;
+                Value::String(value)
+            }
+            Err(_) => Value::Boolean(false),
+        },
+        b'*' => match usize::try_from(length()?) {
+            Ok(len) => {
+                let items = lua.create_table()?;
+                for i in 1..=len {
+                    items.raw_set(i, parse_reply(lua, reply, pos)?)?;
+                }
+                Value::Table(items)
+            }
+            Err(_) => Value::Boolean(false),
+        },
+        other => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Unknown reply type '{}'",
+                other as char
+            )))
+        }
+    })
+}
+
+/// Converts the value a script returned to a RESP2 reply.
+fn to_reply(value: &Value) -> mlua::Result<Vec<u8>> {
+    Ok(match value {
+        Value::String(s) => reply::bulk(&s.to_string_lossy()),
+        Value::Integer(n) => reply::integer(*n),
+        Value::Number(n) => reply::integer(*n as i64),
+        Value::Boolean(true) => reply::integer(1),
+        Value::Table(table) => {
+            if let Value::String(message) = table.raw_get("err")? {
+                return Ok(reply::error(&message.to_string_lossy()));
+            }
+            if let Value::String(status) = table.raw_get("ok")? {
+                return Ok(reply::simple(&status.to_string_lossy()));
+            }
+
+            // Arrays end at the first nil, as in Redis
+            let mut items = Vec::new();
+            for i in 1.. {
+                let item: Value = table.raw_get(i)?;
+                if matches!(item, Value::Nil) {
+                    break;
+                }
+                items.push(to_reply(&item)?);
+            }
+            reply::array(items)
+        }
+        _ => reply::null(),
+    })
+}
+
+/// Finds the error reply a failed script raised, if that is why it failed.
+fn reply_error(error: &mlua::Error) -> Option<&ReplyError> {
+    match error {
+        mlua::Error::CallbackError { cause, .. } => reply_error(cause),
+        mlua::Error::ExternalError(e) => e.downcast_ref::<ReplyError>(),
+        _ => None,
+    }
+}
+
+/// The message of a Lua error, without the stack traceback that would not
+/// fit on the single line of an error reply.
+fn message(error: &mlua::Error) -> String {
+    let message = match error {
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        mlua::Error::RuntimeError(message) => message.clone(),
+        mlua::Error::CallbackError { cause, .. } => return message(cause),
+        other => other.to_string(),
+    };
+    message.lines().next().unwrap_or_default().to_string()
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+/// Runs EVAL or, with `by_digest`, EVALSHA. `args` holds the command name,
+/// the script or its digest, the number of keys, the keys and the arguments.
+pub fn eval(
+    environment: &Arc<Mutex<Environment>>,
+    env: &mut Environment,
+    db: usize,
+    args: &[String],
+    by_digest: bool,
+) -> Vec<u8> {
+    let (keys, argv) = match split_keys(args) {
+        Ok(split) => split,
+        Err(e) => return e,
+    };
+
+    // The interpreter is taken out of the environment while it runs, so the
+    // script's commands can borrow the environment
+    let Some(mut scripting) = env.take_scripting() else {
+        return reply::error(BUSY_ERROR);
+    };
+
+    let sha = if by_digest {
+        let sha = args[1].to_lowercase();
+        scripting
+            .scripts
+            .contains_key(&sha)
+            .then_some(sha)
+            .ok_or_else(|| reply::error("NOSCRIPT No matching script. Please use EVAL."))
+    } else {
+        scripting.load(&args[1])
+    };
+
+    let response = sha.and_then(|sha| {
+        let lua = &scripting.lua;
+        let function = lua
+            .registry_value::<Function>(&scripting.scripts[&sha])
+            .and_then(|function| {
+                // Past the protection against scripts creating globals
+                lua.globals().raw_set("KEYS", keys.to_vec())?;
+                lua.globals().raw_set("ARGV", argv.to_vec())?;
+                Ok(function)
+            })
+            .map_err(|e| reply::error(&format!("ERR {}", message(&e))))?;
+        let name = format!("f_{}", sha);
+        Ok(run(lua, environment, env, db, function, (), false, &name))
+    });
+
+    env.set_scripting(scripting);
+    response.unwrap_or_else(|e| e)
+}
+
+/// Splits the arguments of EVAL or FCALL after the number of keys, at index
+/// 2, into the keys and the remaining arguments.
+fn split_keys(args: &[String]) -> Result<(&[String], &[String]), Vec<u8>> {
+    let numkeys = args[2].parse::<i64>().map_err(|_| reply::not_an_integer())?;
+    if numkeys < 0 {
+        return Err(reply::error("ERR Number of keys can't be negative"));
+    }
+    if numkeys as usize > args.len() - 3 {
+        return Err(reply::error(
+            "ERR Number of keys can't be greater than number of args",
+        ));
+    }
+    Ok(args[3..].split_at(numkeys as usize))
+}
+
+/// Runs a SCRIPT subcommand.
+pub fn script(env: &mut Environment, args: &[String]) -> Vec<u8> {
+    let subcommand = args[1].to_uppercase();
+    if subcommand == "KILL" && args.len() == 2 {
+        return kill();
+    }
+
+    let Some(scripting) = env.scripting_mut() else {
+        return reply::error(BUSY_ERROR);
+    };
+    match subcommand.as_str() {
+        "LOAD" if args.len() == 3 => match scripting.load(&args[2]) {
+            Ok(sha) => reply::bulk(&sha),
+            Err(e) => e,
+        },
+        "EXISTS" if args.len() >= 3 => reply::array(
+            args[2..]
+                .iter()
+                .map(|sha| {
+                    reply::integer(scripting.scripts.contains_key(&sha.to_lowercase()) as i64)
+                })
+                .collect(),
+        ),
+        "FLUSH" if args.len() == 2 => {
+            scripting.flush_scripts();
+            reply::ok()
+        }
+        "FLUSH" if args.len() == 3 => {
+            if !["ASYNC", "SYNC"].contains(&args[2].to_uppercase().as_str()) {
+                return reply::error("ERR SCRIPT FLUSH only support SYNC|ASYNC option");
+            }
+            scripting.flush_scripts();
+            reply::ok()
+        }
+        "HELP" if args.len() == 2 => {
+            let lines = [
+                "SCRIPT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "EXISTS <sha1> [<sha1> ...]",
+                "    Return information about the existence of the scripts in the script cache.",
+                "FLUSH [ASYNC|SYNC]",
+                "    Flush the Lua scripts cache.",
+                "KILL",
+                "    Kill the currently executing Lua script.",
+                "LOAD <script>",
+                "    Load a script into the scripts cache without executing it.",
+                "HELP",
+                "    Print this help.",
+            ];
+            reply::array(lines.iter().map(|line| reply::simple(line)).collect())
+        }
+        _ => reply::error(&format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try SCRIPT HELP.",
+            args[1]
+        )),
+    }
+}
+
+/// Whether a script has been running for longer than its busy threshold, so
+/// other clients are told to wait or to kill it.
+pub fn busy() -> bool {
+    RUNNING.lock().is_ok_and(|running| {
+        running
+            .as_ref()
+            .is_some_and(|r| r.started.elapsed() >= r.threshold)
+    })
+}
+
+/// Asks the running script to stop, unless it already wrote to the dataset,
+/// as stopping it then would leave its changes half applied.
+pub fn kill() -> Vec<u8> {
+    let Ok(mut running) = RUNNING.lock() else {
+        return reply::error("NOTBUSY No scripts in execution right now.");
+    };
+    match running.as_mut() {
+        None => reply::error("NOTBUSY No scripts in execution right now."),
+        Some(running) if running.wrote => reply::error(
+            "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.",
+        ),
+        Some(running) => {
+            running.killed = true;
+            reply::ok()
+        }
+    }
+}